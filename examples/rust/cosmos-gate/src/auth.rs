@@ -0,0 +1,333 @@
+//! Bearer token authentication against a Keycloak realm.
+//!
+//! When configured, `/authorize` requires an `Authorization: Bearer` access
+//! token that is signed by a key in the realm's JWKS, unexpired, and issued
+//! for the configured audience (and, if set, by the configured issuer). The
+//! token's `sub` claim must match the request's `keycloak_id`.
+
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+use anyhow::{bail, Context as _, Result};
+use axum::{
+    extract::{Request, State},
+    http::{header::AUTHORIZATION, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use serde::Deserialize;
+use tokio::{sync::RwLock, time::Instant};
+use tracing::{debug, info, warn};
+
+use crate::ApiError;
+
+/// Default for [`KeycloakAuth::refresh_interval`].
+pub const DEFAULT_JWKS_REFRESH: Duration = Duration::from_secs(300);
+
+/// Minimum time between JWKS fetches triggered by an unknown key id.
+const JWKS_MIN_REFETCH: Duration = Duration::from_secs(10);
+
+/// The validated `sub` claim of the caller's token.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AuthSubject(pub String);
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+}
+
+struct CachedJwks {
+    keys: Arc<JwkSet>,
+    fetched: Instant,
+}
+
+/// Validates Keycloak access tokens against a cached JWKS.
+pub struct KeycloakAuth {
+    jwks_url: String,
+    audience: String,
+    issuer: Option<String>,
+    refresh_interval: Duration,
+    http: reqwest::Client,
+    cache: RwLock<Option<CachedJwks>>,
+}
+
+impl KeycloakAuth {
+    /// `jwks_url` is usually
+    /// `<keycloak>/realms/<realm>/protocol/openid-connect/certs`.
+    pub fn new(jwks_url: impl Into<String>, audience: impl Into<String>) -> Self {
+        Self {
+            jwks_url: jwks_url.into(),
+            audience: audience.into(),
+            issuer: None,
+            refresh_interval: DEFAULT_JWKS_REFRESH,
+            http: reqwest::Client::new(),
+            cache: RwLock::new(None),
+        }
+    }
+
+    /// Also requires tokens' `iss` claim to be `issuer`, usually
+    /// `<keycloak>/realms/<realm>`.
+    pub fn issuer(mut self, issuer: impl Into<String>) -> Self {
+        self.issuer = Some(issuer.into());
+        self
+    }
+
+    /// Sets how long a fetched JWKS is used before it is fetched again.
+    pub fn refresh_interval(mut self, interval: Duration) -> Self {
+        self.refresh_interval = interval;
+        self
+    }
+
+    /// Returns the cached JWKS, fetching it if it is stale. With `refetch`,
+    /// also fetches if the cache is older than [`JWKS_MIN_REFETCH`] so that
+    /// rotated keys are picked up without hammering Keycloak.
+    async fn jwks(&self, refetch: bool) -> Result<Arc<JwkSet>> {
+        let min_age = if refetch {
+            JWKS_MIN_REFETCH
+        } else {
+            self.refresh_interval
+        };
+        if let Some(cached) = &*self.cache.read().await {
+            if cached.fetched.elapsed() < min_age {
+                return Ok(Arc::clone(&cached.keys));
+            }
+        }
+
+        let mut cache = self.cache.write().await;
+        // Another request may have refreshed while we waited for the lock.
+        if let Some(cached) = &*cache {
+            if cached.fetched.elapsed() < min_age {
+                return Ok(Arc::clone(&cached.keys));
+            }
+        }
+        debug!(url = %self.jwks_url, "fetching JWKS");
+        match self.fetch_jwks().await {
+            Ok(keys) => {
+                let keys = Arc::new(keys);
+                *cache = Some(CachedJwks {
+                    keys: Arc::clone(&keys),
+                    fetched: Instant::now(),
+                });
+                Ok(keys)
+            }
+            Err(err) => match &*cache {
+                Some(cached) => {
+                    warn!("unable to refresh JWKS, using cached keys: {err:#}");
+                    Ok(Arc::clone(&cached.keys))
+                }
+                None => Err(err),
+            },
+        }
+    }
+
+    async fn fetch_jwks(&self) -> Result<JwkSet> {
+        self.http
+            .get(&self.jwks_url)
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .context("unable to fetch JWKS")?
+            .json()
+            .await
+            .context("invalid JWKS")
+    }
+
+    /// Validates `token`'s signature, expiry, audience, and issuer, returning
+    /// its subject.
+    pub async fn validate(&self, token: &str) -> Result<AuthSubject> {
+        let header = decode_header(token).context("malformed token")?;
+        if is_shared_secret(header.alg) {
+            bail!("unsupported token algorithm: {:?}", header.alg);
+        }
+        let kid = header.kid.context("token has no key id")?;
+
+        let mut jwks = self.jwks(false).await?;
+        if jwks.find(&kid).is_none() {
+            jwks = self.jwks(true).await?;
+        }
+        let jwk = jwks
+            .find(&kid)
+            .with_context(|| format!("unknown key id: {kid}"))?;
+        let key = DecodingKey::from_jwk(jwk).context("unusable JWK")?;
+
+        // The key, not the token, decides the algorithm; a token whose
+        // header names another one is rejected by `decode`.
+        let mut validation = Validation::new(jwk_algorithm(jwk)?);
+        validation.set_audience(&[&self.audience]);
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        let data = decode::<Claims>(token, &key, &validation).context("invalid token")?;
+        Ok(AuthSubject(data.claims.sub))
+    }
+}
+
+/// The JWKS only holds public keys; never accept a shared-secret MAC.
+fn is_shared_secret(alg: Algorithm) -> bool {
+    matches!(alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512)
+}
+
+/// Returns the signing algorithm `jwk` is for, from its `alg` parameter.
+fn jwk_algorithm(jwk: &Jwk) -> Result<Algorithm> {
+    let alg = jwk.common.key_algorithm.context("JWK has no algorithm")?;
+    let alg = Algorithm::from_str(&alg.to_string())
+        .with_context(|| format!("JWK algorithm {alg} is not a signing algorithm"))?;
+    if is_shared_secret(alg) {
+        bail!("unsupported JWK algorithm: {alg:?}");
+    }
+    Ok(alg)
+}
+
+/// Rejects requests without a valid bearer token and records the token's
+/// subject as an [`AuthSubject`] request extension.
+pub async fn require_bearer(
+    State(auth): State<Arc<KeycloakAuth>>,
+    mut req: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|v| v.trim().to_owned())
+        .ok_or_else(|| {
+            ApiError::new(
+                StatusCode::UNAUTHORIZED,
+                "UNAUTHENTICATED",
+                "missing bearer token",
+            )
+        })?;
+    let subject = auth.validate(&token).await.map_err(|e| {
+        info!("rejecting bearer token: {e:#}");
+        ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "UNAUTHENTICATED",
+            "invalid bearer token",
+        )
+    })?;
+    req.extensions_mut().insert(subject);
+    Ok(next.run(req).await)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::{json, Value};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// A throwaway 2048-bit RSA key, used only to sign test tokens.
+    const TEST_KEY_PEM: &str = include_str!("testdata/jwt_rsa.pem");
+    /// The public modulus of [`TEST_KEY_PEM`], base64url-encoded.
+    const TEST_KEY_N: &str = "2okH6SNxcTcDw9XUAgMYEffs7tzAudEyIOUX3RkpKiwp1K7QlH1zLT3aHKjqW1uHZpakNaDjZM4DMB4j6p0EZjkGdSYQvhd3Pv6pB-D1MOYP65nRxFheEbEkc48RUXerSe5rzbpDWb_ucTs4yDWvvcowUCQKxGMY6Wny2a-kj3jV40ghS7AwpdUYjI5IkNvdaNXIia-7J6FVb2-5czk0nQNnyrMtks85OlSsshB6ifKcF17-0-6qY2pGZMSwJ3pQ0aRcmmZYxGUJdXvN8iqEOwR0hfI7dfBgFTUsfHBD_3mA9CHZ9SpT-qs-2VrZgvRhIjMQYljeGJRzy2jr3fc_SQ";
+    const TEST_KID: &str = "test-key";
+    pub(crate) const TEST_AUDIENCE: &str = "cosmos-gate";
+    const TEST_ISSUER: &str = "https://keycloak.test/realms/gate";
+
+    /// Serves a JWKS holding the test key, for `alg`, and returns its URL.
+    pub(crate) async fn serve_jwks(alg: &str) -> String {
+        let jwks = json!({
+            "keys": [{
+                "kty": "RSA",
+                "use": "sig",
+                "alg": alg,
+                "kid": TEST_KID,
+                "n": TEST_KEY_N,
+                "e": "AQAB",
+            }],
+        });
+        let app = axum::Router::new().route(
+            "/certs",
+            axum::routing::get(move || {
+                let jwks = jwks.clone();
+                async move { axum::Json(jwks) }
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{addr}/certs")
+    }
+
+    /// Signs `claims` with the test key.
+    pub(crate) fn sign(alg: Algorithm, claims: &Value) -> String {
+        let der: String = TEST_KEY_PEM
+            .lines()
+            .filter(|line| !line.starts_with("-----"))
+            .collect();
+        let key = EncodingKey::from_rsa_der(&STANDARD.decode(der).unwrap());
+        let mut header = Header::new(alg);
+        header.kid = Some(TEST_KID.into());
+        encode(&header, claims, &key).unwrap()
+    }
+
+    /// Claims that pass validation for `sub`.
+    pub(crate) fn claims(sub: &str) -> Value {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        json!({ "sub": sub, "aud": TEST_AUDIENCE, "iss": TEST_ISSUER, "exp": now + 3600 })
+    }
+
+    #[tokio::test]
+    async fn test_validate() {
+        let auth = KeycloakAuth::new(serve_jwks("RS256").await, TEST_AUDIENCE).issuer(TEST_ISSUER);
+
+        assert_eq!(
+            auth.validate(&sign(Algorithm::RS256, &claims("alice")))
+                .await
+                .unwrap(),
+            AuthSubject("alice".into())
+        );
+
+        let mut expired = claims("alice");
+        expired["exp"] = json!(expired["exp"].as_u64().unwrap() - 2 * 3600);
+        let mut wrong_audience = claims("alice");
+        wrong_audience["aud"] = json!("another-client");
+        let mut wrong_issuer = claims("alice");
+        wrong_issuer["iss"] = json!("https://keycloak.test/realms/other");
+        for claims in [expired, wrong_audience, wrong_issuer] {
+            let token = sign(Algorithm::RS256, &claims);
+            assert!(auth.validate(&token).await.is_err(), "{claims}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validate_uses_jwk_algorithm() {
+        // The key is published for RS256, so a token claiming RS512 is
+        // rejected even though the signature itself is valid.
+        let auth = KeycloakAuth::new(serve_jwks("RS256").await, TEST_AUDIENCE);
+        let token = sign(Algorithm::RS512, &claims("alice"));
+        assert!(auth.validate(&token).await.is_err());
+
+        let auth = KeycloakAuth::new(serve_jwks("RS512").await, TEST_AUDIENCE);
+        assert!(auth.validate(&token).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_validate_rejects_shared_secret_tokens() {
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some("kid".into());
+        let token = encode(
+            &header,
+            &json!({ "sub": "alice", "aud": "cosmos-gate", "exp": u64::MAX }),
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+
+        // Rejected before the (unreachable) JWKS endpoint is contacted.
+        let auth = KeycloakAuth::new("http://127.0.0.1:9/certs", "cosmos-gate");
+        let err = auth.validate(&token).await.unwrap_err();
+        assert!(err.to_string().contains("unsupported token algorithm"));
+    }
+}
@@ -0,0 +1,361 @@
+//! Settings shared by the cosmos-gate binaries.
+//!
+//! Each setting is resolved with the precedence CLI > environment > config
+//! file > default. The config file is TOML and is located via
+//! [`CONFIG_ENV`]; its keys match the fields of [`PartialConfig`].
+
+use std::{
+    env,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context as _, Result};
+use serde::Deserialize;
+
+use crate::{
+    expand_env_vars, ratelimit::DEFAULT_RATE_BURST, validate_sync_interval, DaemonLogs, DaemonPath,
+    DEFAULT_READY_TIMEOUT, DEFAULT_REQUEST_TIMEOUT, DEFAULT_SYNC_INTERVAL, DEFAULT_TARGET,
+};
+
+/// Environment variable naming the TOML config file.
+pub const CONFIG_ENV: &str = "COSMOS_GATE_CONFIG";
+
+/// Default REST bind address.
+pub const DEFAULT_BIND_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080);
+
+/// Default `tracing` filter.
+pub const DEFAULT_LOG_LEVEL: &str = "info";
+
+/// Settings from a single source, any of which may be unset.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PartialConfig {
+    pub daemon_path: Option<PathBuf>,
+    pub owner_dir: Option<PathBuf>,
+    pub member_dir: Option<PathBuf>,
+    pub bind_addr: Option<SocketAddr>,
+    pub log_level: Option<String>,
+    pub jwks_url: Option<String>,
+    pub audience: Option<String>,
+    pub issuer: Option<String>,
+    pub rate_limit: Option<f64>,
+    pub rate_burst: Option<u32>,
+    pub target: Option<String>,
+    pub sync_interval_ms: Option<u64>,
+    pub request_timeout_ms: Option<u64>,
+    pub ready_timeout_ms: Option<u64>,
+    pub base_path: Option<String>,
+    pub daemon_logs: Option<DaemonLogs>,
+}
+
+impl PartialConfig {
+    /// Reads settings from the `COSMOS_GATE_*` environment variables.
+    pub fn from_env() -> Result<Self> {
+        let bind_addr = env::var("COSMOS_GATE_BIND")
+            .ok()
+            .map(|s| s.parse())
+            .transpose()
+            .context("invalid COSMOS_GATE_BIND")?;
+        let rate_limit = env::var("COSMOS_GATE_RATE_LIMIT")
+            .ok()
+            .map(|s| s.parse())
+            .transpose()
+            .context("invalid COSMOS_GATE_RATE_LIMIT")?;
+        let rate_burst = env::var("COSMOS_GATE_RATE_BURST")
+            .ok()
+            .map(|s| s.parse())
+            .transpose()
+            .context("invalid COSMOS_GATE_RATE_BURST")?;
+        let sync_interval_ms = env::var("COSMOS_GATE_SYNC_INTERVAL_MS")
+            .ok()
+            .map(|s| s.parse())
+            .transpose()
+            .context("invalid COSMOS_GATE_SYNC_INTERVAL_MS")?;
+        let request_timeout_ms = env::var("COSMOS_GATE_REQUEST_TIMEOUT_MS")
+            .ok()
+            .map(|s| s.parse())
+            .transpose()
+            .context("invalid COSMOS_GATE_REQUEST_TIMEOUT_MS")?;
+        let ready_timeout_ms = env::var("COSMOS_GATE_READY_TIMEOUT_MS")
+            .ok()
+            .map(|s| s.parse())
+            .transpose()
+            .context("invalid COSMOS_GATE_READY_TIMEOUT_MS")?;
+        let daemon_logs = env::var("COSMOS_GATE_DAEMON_LOGS")
+            .ok()
+            .map(|s| s.parse())
+            .transpose()
+            .context("invalid COSMOS_GATE_DAEMON_LOGS")?;
+        Ok(Self {
+            daemon_path: env::var_os("COSMOS_GATE_DAEMON").map(PathBuf::from),
+            owner_dir: env::var_os("COSMOS_GATE_OWNER_DIR").map(PathBuf::from),
+            member_dir: env::var_os("COSMOS_GATE_MEMBER_DIR").map(PathBuf::from),
+            bind_addr,
+            log_level: env::var("COSMOS_GATE_LOG").ok(),
+            jwks_url: env::var("COSMOS_GATE_JWKS_URL").ok(),
+            audience: env::var("COSMOS_GATE_AUDIENCE").ok(),
+            issuer: env::var("COSMOS_GATE_ISSUER").ok(),
+            rate_limit,
+            rate_burst,
+            target: env::var("COSMOS_GATE_TARGET").ok(),
+            sync_interval_ms,
+            request_timeout_ms,
+            ready_timeout_ms,
+            base_path: env::var("COSMOS_GATE_BASE_PATH").ok(),
+            daemon_logs,
+        })
+    }
+
+    /// Reads settings from the TOML file at `path`.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let buf = std::fs::read_to_string(path)
+            .with_context(|| format!("unable to read config file: {}", path.display()))?;
+        toml::from_str(&buf).with_context(|| format!("invalid config file: {}", path.display()))
+    }
+
+    /// Reads settings from the file named by [`CONFIG_ENV`], if set.
+    pub fn from_config_env() -> Result<Self> {
+        match env::var_os(CONFIG_ENV) {
+            Some(path) => Self::from_file(Path::new(&path)),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// Fills each unset field from `fallback`.
+    fn or(self, fallback: Self) -> Self {
+        Self {
+            daemon_path: self.daemon_path.or(fallback.daemon_path),
+            owner_dir: self.owner_dir.or(fallback.owner_dir),
+            member_dir: self.member_dir.or(fallback.member_dir),
+            bind_addr: self.bind_addr.or(fallback.bind_addr),
+            log_level: self.log_level.or(fallback.log_level),
+            jwks_url: self.jwks_url.or(fallback.jwks_url),
+            audience: self.audience.or(fallback.audience),
+            issuer: self.issuer.or(fallback.issuer),
+            rate_limit: self.rate_limit.or(fallback.rate_limit),
+            rate_burst: self.rate_burst.or(fallback.rate_burst),
+            target: self.target.or(fallback.target),
+            sync_interval_ms: self.sync_interval_ms.or(fallback.sync_interval_ms),
+            request_timeout_ms: self.request_timeout_ms.or(fallback.request_timeout_ms),
+            ready_timeout_ms: self.ready_timeout_ms.or(fallback.ready_timeout_ms),
+            base_path: self.base_path.or(fallback.base_path),
+            daemon_logs: self.daemon_logs.or(fallback.daemon_logs),
+        }
+    }
+}
+
+/// Fully resolved settings.
+///
+/// Settings without a default stay optional here; each binary requires the
+/// ones it needs through the accessors.
+#[derive(Clone, Debug)]
+pub struct ResolvedConfig {
+    daemon_path: Option<PathBuf>,
+    owner_dir: Option<PathBuf>,
+    member_dir: Option<PathBuf>,
+    pub bind_addr: SocketAddr,
+    pub log_level: String,
+    /// Keycloak JWKS endpoint; if unset, `/authorize` is unauthenticated.
+    pub jwks_url: Option<String>,
+    /// Required token audience when `jwks_url` is set.
+    pub audience: Option<String>,
+    /// If set, the required token issuer when `jwks_url` is set.
+    pub issuer: Option<String>,
+    /// Requests per second allowed per `keycloak_id`; if unset, requests
+    /// are not rate limited.
+    pub rate_limit: Option<f64>,
+    /// Burst size for `rate_limit`.
+    pub rate_burst: u32,
+    /// COSMOS target name routed to the member onboarded by the init binary.
+    pub target: String,
+    sync_interval: Duration,
+    /// Upper bound on `/authorize` processing; see
+    /// [`AppState::request_timeout`](crate::AppState::request_timeout).
+    pub request_timeout: Duration,
+    /// How long `/ready` waits on the owner daemon; see
+    /// [`AppState::ready_timeout`](crate::AppState::ready_timeout).
+    pub ready_timeout: Duration,
+    /// Path prefix the REST routes are nested under, e.g. `/cosmos-gate`.
+    pub base_path: Option<String>,
+    /// Where spawned daemons' stdout and stderr go.
+    pub daemon_logs: DaemonLogs,
+}
+
+impl ResolvedConfig {
+    /// Resolves `cli` against the environment and the config file.
+    pub fn load(cli: PartialConfig) -> Result<Self> {
+        Ok(resolve_config(
+            cli,
+            PartialConfig::from_env()?,
+            PartialConfig::from_config_env()?,
+        ))
+    }
+
+    pub fn daemon_path(&self) -> Result<DaemonPath> {
+        required(&self.daemon_path, "daemon_path", "COSMOS_GATE_DAEMON").map(DaemonPath)
+    }
+
+    /// The owner's work dir, with `${VAR}` references expanded.
+    pub fn owner_dir(&self) -> Result<PathBuf> {
+        expand_env_vars(&required(
+            &self.owner_dir,
+            "owner_dir",
+            "COSMOS_GATE_OWNER_DIR",
+        )?)
+    }
+
+    /// The member's work dir, with `${VAR}` references expanded.
+    pub fn member_dir(&self) -> Result<PathBuf> {
+        expand_env_vars(&required(
+            &self.member_dir,
+            "member_dir",
+            "COSMOS_GATE_MEMBER_DIR",
+        )?)
+    }
+
+    /// Interval at which onboarded peers sync; at least
+    /// [`MIN_SYNC_INTERVAL`](crate::MIN_SYNC_INTERVAL).
+    pub fn sync_interval(&self) -> Result<Duration> {
+        validate_sync_interval(self.sync_interval)
+    }
+}
+
+fn required(value: &Option<PathBuf>, key: &str, var: &str) -> Result<PathBuf> {
+    value.clone().with_context(|| {
+        format!(
+            "missing <{key}>: pass it as an argument, set {var}, or set `{key}` in the config file"
+        )
+    })
+}
+
+/// Merges settings with the precedence `cli` > `env` > `file` > default.
+pub fn resolve_config(
+    cli: PartialConfig,
+    env: PartialConfig,
+    file: PartialConfig,
+) -> ResolvedConfig {
+    let merged = cli.or(env).or(file);
+    ResolvedConfig {
+        daemon_path: merged.daemon_path,
+        owner_dir: merged.owner_dir,
+        member_dir: merged.member_dir,
+        bind_addr: merged.bind_addr.unwrap_or(DEFAULT_BIND_ADDR),
+        log_level: merged
+            .log_level
+            .unwrap_or_else(|| DEFAULT_LOG_LEVEL.to_string()),
+        jwks_url: merged.jwks_url,
+        audience: merged.audience,
+        issuer: merged.issuer,
+        rate_limit: merged.rate_limit,
+        rate_burst: merged.rate_burst.unwrap_or(DEFAULT_RATE_BURST),
+        target: merged.target.unwrap_or_else(|| DEFAULT_TARGET.to_string()),
+        sync_interval: merged
+            .sync_interval_ms
+            .map_or(DEFAULT_SYNC_INTERVAL, Duration::from_millis),
+        request_timeout: merged
+            .request_timeout_ms
+            .map_or(DEFAULT_REQUEST_TIMEOUT, Duration::from_millis),
+        ready_timeout: merged
+            .ready_timeout_ms
+            .map_or(DEFAULT_READY_TIMEOUT, Duration::from_millis),
+        base_path: merged.base_path,
+        daemon_logs: merged.daemon_logs.unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_config_precedence() {
+        let cli = PartialConfig {
+            daemon_path: Some("/cli/daemon".into()),
+            ..Default::default()
+        };
+        let env = PartialConfig {
+            daemon_path: Some("/env/daemon".into()),
+            owner_dir: Some("/env/owner".into()),
+            ..Default::default()
+        };
+        let file = PartialConfig {
+            owner_dir: Some("/file/owner".into()),
+            member_dir: Some("/file/member".into()),
+            log_level: Some("debug".into()),
+            ..Default::default()
+        };
+
+        let cfg = resolve_config(cli, env, file);
+        assert_eq!(cfg.daemon_path().unwrap().0, Path::new("/cli/daemon"));
+        assert_eq!(cfg.owner_dir().unwrap(), Path::new("/env/owner"));
+        assert_eq!(cfg.member_dir().unwrap(), Path::new("/file/member"));
+        assert_eq!(cfg.log_level, "debug");
+        assert_eq!(cfg.bind_addr, DEFAULT_BIND_ADDR);
+    }
+
+    #[test]
+    fn test_resolve_config_missing() {
+        let cfg = resolve_config(
+            PartialConfig::default(),
+            PartialConfig::default(),
+            PartialConfig::default(),
+        );
+        assert!(cfg.daemon_path().is_err());
+        assert_eq!(cfg.log_level, DEFAULT_LOG_LEVEL);
+        assert_eq!(cfg.sync_interval().unwrap(), DEFAULT_SYNC_INTERVAL);
+        assert_eq!(cfg.request_timeout, DEFAULT_REQUEST_TIMEOUT);
+        assert_eq!(cfg.ready_timeout, DEFAULT_READY_TIMEOUT);
+    }
+
+    #[test]
+    fn test_resolve_config_expands_dirs() {
+        env::set_var("COSMOS_GATE_TEST_STATE", "/var/lib/gate");
+        let cfg = resolve_config(
+            PartialConfig {
+                owner_dir: Some("${COSMOS_GATE_TEST_STATE}/owner".into()),
+                member_dir: Some("${COSMOS_GATE_TEST_STATE}/member".into()),
+                ..Default::default()
+            },
+            PartialConfig::default(),
+            PartialConfig::default(),
+        );
+        assert_eq!(cfg.owner_dir().unwrap(), Path::new("/var/lib/gate/owner"));
+        assert_eq!(cfg.member_dir().unwrap(), Path::new("/var/lib/gate/member"));
+    }
+
+    #[test]
+    fn test_sync_interval_minimum() {
+        let cfg = |ms| {
+            resolve_config(
+                PartialConfig {
+                    sync_interval_ms: Some(ms),
+                    ..Default::default()
+                },
+                PartialConfig::default(),
+                PartialConfig::default(),
+            )
+        };
+        assert_eq!(cfg(50).sync_interval().unwrap(), Duration::from_millis(50));
+        assert!(cfg(49).sync_interval().is_err());
+    }
+
+    #[test]
+    fn test_partial_config_from_toml() {
+        let cfg: PartialConfig = toml::from_str(
+            r#"
+            daemon_path = "/usr/bin/aranya-daemon"
+            bind_addr = "0.0.0.0:9000"
+            daemon_logs = "inherit"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            cfg.daemon_path.unwrap(),
+            Path::new("/usr/bin/aranya-daemon")
+        );
+        assert_eq!(cfg.bind_addr.unwrap().port(), 9000);
+        assert_eq!(cfg.daemon_logs, Some(DaemonLogs::Inherit));
+    }
+}
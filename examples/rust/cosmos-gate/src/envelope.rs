@@ -0,0 +1,214 @@
+//! Signed envelopes that let a dispatcher prove a serialized command came
+//! from this gate.
+//!
+//! The gate signs `CONTEXT || ts || nonce || command` with an Ed25519 key
+//! kept in the owner work dir. The verifying key is written next to it so it
+//! can be distributed to whoever checks envelopes.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context as _, Result};
+use aranya_client::client::Client;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use ed25519_dalek::{Signature, Signer as _, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use tokio::{fs, io::AsyncWriteExt as _};
+
+use crate::write_atomic;
+
+/// Domain separation for envelope signatures.
+const CONTEXT: &[u8] = b"cosmos-gate envelope v1";
+
+/// Size in bytes of [`SignedEnvelope::nonce`].
+pub const NONCE_LEN: usize = 16;
+
+/// A serialized command signed by the gate.
+///
+/// Binary fields are standard base64. Verifiers should also reject stale
+/// `ts` values and reused nonces; [`verify_envelope`] only checks the
+/// signature.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SignedEnvelope {
+    pub command: String,
+    pub signature: String,
+    /// Milliseconds since the Unix epoch when the envelope was signed.
+    pub ts: u64,
+    pub nonce: String,
+}
+
+fn signed_message(command: &[u8], ts: u64, nonce: &[u8; NONCE_LEN]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(CONTEXT.len() + 8 + NONCE_LEN + command.len());
+    msg.extend_from_slice(CONTEXT);
+    msg.extend_from_slice(&ts.to_be_bytes());
+    msg.extend_from_slice(nonce);
+    msg.extend_from_slice(command);
+    msg
+}
+
+/// Signs `command` with the gate's `key`.
+pub fn seal(key: &SigningKey, command: &[u8], nonce: [u8; NONCE_LEN]) -> Result<SignedEnvelope> {
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("system clock is before the Unix epoch")?
+        .as_millis();
+    let ts = u64::try_from(ts).context("timestamp out of range")?;
+    let sig = key.sign(&signed_message(command, ts, &nonce));
+    Ok(SignedEnvelope {
+        command: STANDARD.encode(command),
+        signature: STANDARD.encode(sig.to_bytes()),
+        ts,
+        nonce: STANDARD.encode(nonce),
+    })
+}
+
+/// Verifies `envelope` against the gate's `key` and returns the command
+/// bytes.
+pub fn verify_envelope(key: &VerifyingKey, envelope: &SignedEnvelope) -> Result<Vec<u8>> {
+    let command = STANDARD
+        .decode(&envelope.command)
+        .context("invalid command encoding")?;
+    let nonce = STANDARD
+        .decode(&envelope.nonce)
+        .context("invalid nonce encoding")?;
+    let Ok(nonce) = <[u8; NONCE_LEN]>::try_from(nonce.as_slice()) else {
+        bail!("invalid nonce length: {}", nonce.len());
+    };
+    let sig = STANDARD
+        .decode(&envelope.signature)
+        .context("invalid signature encoding")?;
+    let sig = Signature::from_slice(&sig).context("invalid signature")?;
+    key.verify_strict(&signed_message(&command, envelope.ts, &nonce), &sig)
+        .context("envelope signature verification failed")?;
+    Ok(command)
+}
+
+pub fn signing_key_path(owner_dir: &Path) -> PathBuf {
+    owner_dir.join(".aranya_gate_signing_key")
+}
+pub fn verifying_key_path(owner_dir: &Path) -> PathBuf {
+    owner_dir.join(".aranya_gate_verifying_key")
+}
+
+/// Loads the gate signing key from `owner_dir`, generating it with the
+/// daemon's RNG on first use.
+///
+/// The verifying key file is rewritten if it is missing.
+pub async fn load_or_create_signing_key(client: &Client, owner_dir: &Path) -> Result<SigningKey> {
+    let path = signing_key_path(owner_dir);
+    let key = match fs::read(&path).await {
+        Ok(buf) => {
+            let seed = <[u8; 32]>::try_from(buf.as_slice())
+                .ok()
+                .context("invalid signing key file")?;
+            SigningKey::from_bytes(&seed)
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            let mut seed = [0u8; 32];
+            client.rand(&mut seed).await;
+            write_secret(&path, &seed)
+                .await
+                .context("unable to write signing key file")?;
+            SigningKey::from_bytes(&seed)
+        }
+        Err(err) => return Err(err).context("unable to read signing key file"),
+    };
+
+    let verifying_path = verifying_key_path(owner_dir);
+    if !fs::try_exists(&verifying_path)
+        .await
+        .context("unable to check for verifying key file")?
+    {
+        write_atomic(
+            &verifying_path,
+            STANDARD.encode(key.verifying_key().to_bytes()),
+        )
+        .await?;
+    }
+    Ok(key)
+}
+
+/// Like [`write_atomic`], but the file is only ever readable by its owner.
+async fn write_secret(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    // A leftover temp file may have looser permissions; start fresh.
+    match fs::remove_file(&tmp).await {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err).with_context(|| format!("unable to remove {}", tmp.display())),
+    }
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp)
+        .await
+        .with_context(|| format!("unable to create {}", tmp.display()))?;
+    file.write_all(contents)
+        .await
+        .with_context(|| format!("unable to write {}", tmp.display()))?;
+    file.sync_all()
+        .await
+        .with_context(|| format!("unable to sync {}", tmp.display()))?;
+    fs::rename(&tmp, path)
+        .await
+        .with_context(|| format!("unable to rename {} into place", tmp.display()))?;
+    Ok(())
+}
+
+/// Reads a base64 verifying key written by [`load_or_create_signing_key`].
+pub async fn read_verifying_key(path: &Path) -> Result<VerifyingKey> {
+    let s = fs::read_to_string(path)
+        .await
+        .context("unable to read verifying key file")?;
+    let buf = STANDARD
+        .decode(s.trim())
+        .context("invalid verifying key encoding")?;
+    let bytes = <[u8; 32]>::try_from(buf.as_slice())
+        .ok()
+        .context("invalid verifying key length")?;
+    VerifyingKey::from_bytes(&bytes).context("invalid verifying key")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_write_secret_is_owner_only() {
+        let dir = tempdir().unwrap();
+        let path = signing_key_path(dir.path());
+        write_secret(&path, &[7u8; 32]).await.unwrap();
+        let meta = fs::metadata(&path).await.unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::read(&path).await.unwrap(), [7u8; 32]);
+    }
+
+    #[test]
+    fn test_envelope_round_trip() {
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+        let envelope = seal(&key, b"command bytes", [1u8; NONCE_LEN]).unwrap();
+        let got = verify_envelope(&key.verifying_key(), &envelope).unwrap();
+        assert_eq!(got, b"command bytes");
+
+        let mut tampered = envelope.clone();
+        tampered.command = STANDARD.encode(b"other bytes");
+        assert!(verify_envelope(&key.verifying_key(), &tampered).is_err());
+
+        let mut tampered = envelope.clone();
+        tampered.ts += 1;
+        assert!(verify_envelope(&key.verifying_key(), &tampered).is_err());
+
+        let other = SigningKey::from_bytes(&[8u8; 32]);
+        assert!(verify_envelope(&other.verifying_key(), &envelope).is_err());
+    }
+}
@@ -1,178 +1,256 @@
+pub mod auth;
+pub mod config;
+pub mod envelope;
+pub mod ratelimit;
+pub mod reconnect;
+
 use std::{
-    net::{Ipv4Addr, SocketAddr},
+    collections::{BTreeMap, HashMap},
+    marker::PhantomData,
+    num::IntErrorKind,
     path::{Path, PathBuf},
     str::FromStr,
     sync::Arc,
     time::Duration,
 };
 
-use anyhow::{Context as _, Result};
+use anyhow::{bail, Context as _, Result};
 use aranya_client::{
-    client::{Client, DeviceId, KeyBundle},
-    AddTeamConfig, AddTeamQuicSyncConfig, CreateTeamConfig, CreateTeamQuicSyncConfig, SyncPeerConfig,
-    TeamId,
+    client::{Client, DeviceId, KeyBundle, NetIdentifier, Team},
+    AddTeamConfig, AddTeamQuicSyncConfig, CreateTeamConfig, CreateTeamQuicSyncConfig,
+    SyncPeerConfig, TeamId,
+};
+pub use aranya_example_common::{
+    connect_client, expand_env_vars, instance_name, uds_path, wait_for_uds, AfcConfig, AqcConfig,
+    ClientCtx, Daemon, DaemonConfig, DaemonLogs, DaemonPath, QuicSyncConfig, SyncConfig,
+    DAEMON_START_TIMEOUT, DEFAULT_SHUTDOWN_GRACE,
 };
-use aranya_util::Addr;
 use aranya_policy_text::Text;
-use axum::{extract::State, http::StatusCode, response::{IntoResponse, Response}, routing::post, Json, Router};
-use axum::http::header::CONTENT_TYPE;
-use backon::{ExponentialBuilder, Retryable};
-use rustix::shm;
-use serde::Deserialize;
-use tokio::{fs, process::Child, process::Command, time::sleep};
+use axum::{
+    extract::{
+        rejection::{JsonRejection, QueryRejection},
+        Query, State,
+    },
+    http::{
+        header::{CONTENT_TYPE, RETRY_AFTER},
+        HeaderMap, StatusCode,
+    },
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use ed25519_dalek::SigningKey;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::{
+    fs,
+    time::{sleep, timeout, Instant},
+};
 use tracing::{debug, info};
 
-#[derive(Clone, Debug)]
-pub struct DaemonPath(pub PathBuf);
+// Convenience helpers for state files.
+pub fn init_marker_path(owner_dir: &Path) -> PathBuf {
+    owner_dir.join(".aranya_initialized")
+}
+pub fn team_id_path(owner_dir: &Path) -> PathBuf {
+    owner_dir.join(".aranya_team_id")
+}
+/// The owner's AQC network identifier, as last assigned by onboarding or
+/// [`refresh_owner_net_identifier`].
+pub fn owner_net_id_path(owner_dir: &Path) -> PathBuf {
+    owner_dir.join(".aranya_owner_aqc_net_id")
+}
+pub fn member_id_path(owner_dir: &Path) -> PathBuf {
+    owner_dir.join(".aranya_member_id")
+}
+/// Maps COSMOS target names to the member device that executes their
+/// commands.
+pub fn targets_path(owner_dir: &Path) -> PathBuf {
+    owner_dir.join(".aranya_targets.json")
+}
 
-#[derive(Debug)]
-#[clippy::has_significant_drop]
-pub struct Daemon {
-    // NB: This has important drop side effects.
-    _proc: Child,
-    _work_dir: PathBuf,
-}
-
-impl Daemon {
-    pub async fn spawn(path: &DaemonPath, user_name: &str, work_dir: &Path) -> Result<Self> {
-        fs::create_dir_all(&work_dir).await?;
-
-        // Prepare daemon dirs and config.
-        let shm = format!("/shm_{}", user_name);
-        // Ensure no stale POSIX SHM exists from previous runs (matches aranya example).
-        let _ = shm::unlink(&shm);
-
-        let runtime_dir = work_dir.join("run");
-        let state_dir = work_dir.join("state");
-        let cache_dir = work_dir.join("cache");
-        let logs_dir = work_dir.join("logs");
-        let config_dir = work_dir.join("config");
-        for dir in &[&runtime_dir, &state_dir, &cache_dir, &logs_dir, &config_dir] {
-            fs::create_dir_all(dir)
-                .await
-                .with_context(|| format!("unable to create directory: {}", dir.display()))?;
-        }
+/// Default COSMOS target name for the member onboarded by the init binary.
+pub const DEFAULT_TARGET: &str = "INST";
 
-        let cfg_path = work_dir.join("config.toml");
-        let cfg_buf = format!(
-            r#"
-            name = {user_name:?}
-            runtime_dir = {runtime_dir:?}
-            state_dir = {state_dir:?}
-            cache_dir = {cache_dir:?}
-            logs_dir = {logs_dir:?}
-            config_dir = {config_dir:?}
-
-            aqc.enable = true
-
-            [afc]
-            enable = true
-            shm_path = {shm:?}
-            max_chans = 100
-
-            [sync.quic]
-            enable = true
-            addr = "127.0.0.1:0"
-            "#
-        );
-        fs::write(&cfg_path, cfg_buf).await?;
-
-        // Spawn daemon.
-        let cfg_path = cfg_path.as_os_str().to_str().context("cfg_path UTF-8")?;
-        let mut cmd = Command::new(&path.0);
-        cmd.kill_on_drop(true)
-            .current_dir(work_dir)
-            .args(["--config", cfg_path]);
-        debug!(?cmd, "spawning daemon");
-        let proc = cmd.spawn().context("unable to spawn daemon")?;
-        Ok(Daemon {
-            _proc: proc,
-            _work_dir: work_dir.into(),
-        })
-    }
+/// Normalizes a COSMOS target name for lookup; target names are
+/// case-insensitive.
+pub fn normalize_target(target: &str) -> String {
+    target.trim().to_uppercase()
 }
 
-pub struct ClientCtx {
-    pub client: Arc<Client>,
-    pub pk: KeyBundle,
-    pub id: DeviceId,
-    // keep daemon alive
-    _work_dir: PathBuf,
-    _daemon: Daemon,
+/// Reads the target map written by [`write_targets`].
+pub async fn read_targets(path: &Path) -> Result<HashMap<String, DeviceId>> {
+    let buf = fs::read_to_string(path)
+        .await
+        .with_context(|| format!("unable to read {}", path.display()))?;
+    let raw: BTreeMap<String, String> = serde_json::from_str(&buf)
+        .with_context(|| format!("invalid targets file: {}", path.display()))?;
+    raw.into_iter()
+        .map(|(target, id)| {
+            let id = id
+                .parse::<DeviceId>()
+                .with_context(|| format!("invalid device id for target {target}"))?;
+            Ok((normalize_target(&target), id))
+        })
+        .collect()
 }
 
-impl ClientCtx {
-    pub async fn new(user_name: &str, daemon_path: &DaemonPath, work_dir: PathBuf) -> Result<Self> {
-        info!(user_name, "creating `ClientCtx`");
+/// Writes `targets` as a JSON object of target name to device id.
+pub async fn write_targets(path: &Path, targets: &HashMap<String, DeviceId>) -> Result<()> {
+    let raw: BTreeMap<_, _> = targets
+        .iter()
+        .map(|(target, id)| (normalize_target(target), id.to_string()))
+        .collect();
+    write_atomic(path, serde_json::to_vec_pretty(&raw)?).await
+}
 
-        // Spawn daemon in given work_dir.
-        let daemon = Daemon::spawn(daemon_path, user_name, &work_dir).await?;
+/// Roster of onboarded members; see [`MemberRecord`].
+pub fn members_path(owner_dir: &Path) -> PathBuf {
+    owner_dir.join("members.json")
+}
 
-        // UDS path the daemon listens on.
-        let uds_sock = work_dir.join("run").join("uds.sock");
+/// An onboarded member, as recorded in [`members_path`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MemberRecord {
+    pub name: String,
+    #[serde(with = "device_id_str")]
+    pub device_id: DeviceId,
+    /// The member's AQC network identifier, if it runs an AQC server.
+    ///
+    /// This is the address the member's AQC server had during onboarding;
+    /// see [`refresh_owner_net_identifier`] for why it goes stale.
+    pub aqc_net_id: Option<String>,
+}
 
-        // Give the daemon a moment to start and bind its UDS.
-        sleep(Duration::from_millis(100)).await;
+/// (De)serializes a [`DeviceId`] as its string form.
+mod device_id_str {
+    use serde::{Deserialize as _, Deserializer, Serializer};
 
-        // Connect client.
-        let any_addr = Addr::from((Ipv4Addr::LOCALHOST, 0));
-        let client = (|| {
-            Client::builder()
-                .daemon_uds_path(&uds_sock)
-                .aqc_server_addr(&any_addr)
-                .connect()
-        })
-        .retry(ExponentialBuilder::default())
-        .await
-        .context("unable to initialize client")?;
+    use super::DeviceId;
 
-        // Fetch client identity info.
-        let pk = client
-            .get_key_bundle()
-            .await
-            .context("expected key bundle")?;
-        let id = client.get_device_id().await.context("expected device id")?;
-
-        Ok(Self {
-            client: Arc::new(client),
-            pk,
-            id,
-            _work_dir: work_dir,
-            _daemon: daemon,
-        })
+    pub fn serialize<S: Serializer>(id: &DeviceId, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(id)
     }
 
-    pub async fn aranya_local_addr(&self) -> Result<SocketAddr> {
-        Ok(self.client.local_addr().await?)
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<DeviceId, D::Error> {
+        String::deserialize(d)?
+            .parse()
+            .map_err(serde::de::Error::custom)
     }
 }
 
-// Convenience helpers for state files.
-pub fn init_marker_path(owner_dir: &Path) -> PathBuf {
-    owner_dir.join(".aranya_initialized")
-}
-pub fn team_id_path(owner_dir: &Path) -> PathBuf {
-    owner_dir.join(".aranya_team_id")
+/// Reads the member roster, which is empty if it does not exist yet.
+pub async fn read_members(path: &Path) -> Result<Vec<MemberRecord>> {
+    let buf = match fs::read_to_string(path).await {
+        Ok(buf) => buf,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).with_context(|| format!("unable to read {}", path.display())),
+    };
+    serde_json::from_str(&buf).with_context(|| format!("invalid members file: {}", path.display()))
 }
-// NEW: member_id file path and reader
-pub fn member_id_path(owner_dir: &Path) -> PathBuf {
-    owner_dir.join(".aranya_member_id")
+
+/// Adds `member` to the roster, replacing any record with the same device
+/// id so that re-running onboarding does not duplicate entries.
+pub async fn append_member(path: &Path, member: MemberRecord) -> Result<()> {
+    let mut members = read_members(path).await?;
+    members.retain(|m| m.device_id.__id != member.device_id.__id);
+    members.push(member);
+    write_atomic(path, serde_json::to_vec_pretty(&members)?).await
 }
+
 pub async fn read_member_id(path: &Path) -> Result<DeviceId> {
-    let s = fs::read_to_string(path).await.context("unable to read member_id file")?;
-    s.trim().parse::<DeviceId>().context("invalid member_id in file")
+    read_id(path, "member_id").await
 }
 pub async fn read_team_id(path: &Path) -> Result<TeamId> {
-    let s = fs::read_to_string(path).await.context("unable to read team_id file")?;
-    s.trim().parse::<TeamId>().context("invalid team_id in file")
+    read_id(path, "team_id").await
+}
+
+/// Number of times [`read_id`] reads a file that fails to parse.
+const READ_ID_ATTEMPTS: usize = 5;
+
+/// Reads and parses an id file, retrying briefly on parse failure in case a
+/// non-atomic writer is mid-write.
+async fn read_id<T>(path: &Path, what: &str) -> Result<T>
+where
+    T: FromStr<Err = anyhow::Error>,
+{
+    let mut attempt = 1;
+    loop {
+        let s = fs::read_to_string(path)
+            .await
+            .with_context(|| format!("unable to read {what} file"))?;
+        match s.trim().parse::<T>() {
+            Ok(id) => return Ok(id),
+            Err(err) if attempt < READ_ID_ATTEMPTS => {
+                debug!(attempt, %err, "unable to parse {what} file; retrying");
+                attempt += 1;
+                sleep(Duration::from_millis(20)).await;
+            }
+            Err(err) => return Err(err).with_context(|| format!("invalid {what} in file")),
+        }
+    }
+}
+
+/// Writes `contents` to `path` by writing a sibling temp file and renaming
+/// it into place, so readers never observe a partial write.
+pub async fn write_atomic(path: &Path, contents: impl AsRef<[u8]>) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    fs::write(&tmp, contents)
+        .await
+        .with_context(|| format!("unable to write {}", tmp.display()))?;
+    fs::rename(&tmp, path)
+        .await
+        .with_context(|| format!("unable to rename {} into place", tmp.display()))?;
+    Ok(())
 }
 
 #[derive(Clone)]
 pub struct AppState {
-    pub owner: Arc<Client>,
+    pub owner: Arc<reconnect::ReconnectingClient>,
     pub owner_team_id: TeamId,
-    // REPLACED: was `target_member: Arc<Client>`
-    pub target_member_id: DeviceId,
+    /// Member device for each normalized COSMOS target name; see
+    /// [`normalize_target`].
+    pub targets: Arc<HashMap<String, DeviceId>>,
+    /// Upper bound on request processing. A shorter [`DEADLINE_HEADER`]
+    /// from the dispatcher takes precedence.
+    pub request_timeout: Duration,
+    /// How long `GET /ready` waits on the owner daemon, including a
+    /// reconnect.
+    pub ready_timeout: Duration,
+    /// If set, commands are returned as a JSON [`envelope::SignedEnvelope`]
+    /// signed with this key instead of raw bytes.
+    pub envelope_key: Option<Arc<SigningKey>>,
+    /// If set, `/authorize` requires a Keycloak bearer token whose subject
+    /// matches [`CMDSummary::keycloak_id`].
+    pub auth: Option<Arc<auth::KeycloakAuth>>,
+    /// If set, limits how often each `keycloak_id` may call `/authorize`.
+    pub rate_limit: Option<Arc<ratelimit::RateLimiter>>,
+}
+
+/// Default for [`AppState::request_timeout`].
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default for [`AppState::ready_timeout`].
+pub const DEFAULT_READY_TIMEOUT: Duration = reconnect::DEFAULT_PING_TIMEOUT;
+
+/// Request header carrying the dispatcher's deadline in milliseconds.
+pub const DEADLINE_HEADER: &str = "x-deadline-ms";
+
+/// Returns the deadline from [`DEADLINE_HEADER`], capped at `limit`, or
+/// `limit` if the header is absent.
+pub fn request_deadline(headers: &HeaderMap, limit: Duration) -> Result<Duration> {
+    let Some(value) = headers.get(DEADLINE_HEADER) else {
+        return Ok(limit);
+    };
+    let ms = value
+        .to_str()
+        .ok()
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .with_context(|| format!("invalid {DEADLINE_HEADER} header: {value:?}"))?;
+    Ok(Duration::from_millis(ms).min(limit))
 }
 
 // Map summary object of dispatcher POST requests.
@@ -186,156 +264,1244 @@ pub struct CMDSummary {
     pub function_code: u16,
 }
 
-pub fn deserialize_hex_u16<'de, D>(deserializer: D) -> Result<u16, D::Error>
+/// Unsigned integer types accepted by [`deserialize_hex`].
+pub trait HexInt: Sized + TryFrom<u64> {
+    /// Width of the type, for error messages.
+    const BITS: u32;
+
+    fn from_hex(s: &str) -> Result<Self, std::num::ParseIntError>;
+}
+
+macro_rules! impl_hex_int {
+    ($($t:ty),* $(,)?) => {
+        $(
+            impl HexInt for $t {
+                const BITS: u32 = <$t>::BITS;
+
+                fn from_hex(s: &str) -> Result<Self, std::num::ParseIntError> {
+                    <$t>::from_str_radix(s, 16)
+                }
+            }
+        )*
+    };
+}
+impl_hex_int!(u8, u16, u32, u64);
+
+/// Deserializes an unsigned integer from a hex string (`"0x1A2B"`,
+/// `"0X1A2B"`, or bare `"1A2B"`) or from an integer, rejecting values that do
+/// not fit in `T`.
+pub fn deserialize_hex<'de, D, T>(deserializer: D) -> Result<T, D::Error>
 where
     D: serde::de::Deserializer<'de>,
+    T: HexInt,
 {
-    struct HexVisitor;
-    impl<'de> serde::de::Visitor<'de> for HexVisitor {
-        type Value = u16;
-        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-            write!(f, "a hex string (e.g., \"0x1A2B\" or \"1A2B\") or a number 0-65535")
+    struct HexVisitor<T>(PhantomData<T>);
+    impl<T: HexInt> serde::de::Visitor<'_> for HexVisitor<T> {
+        type Value = T;
+        fn expecting(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(
+                f,
+                "a hex string (e.g., \"0x1A\" or \"1A\") or an integer that fits in u{}",
+                T::BITS
+            )
         }
-        fn visit_u64<E>(self, v: u64) -> Result<u16, E>
+        fn visit_u64<E>(self, v: u64) -> Result<T, E>
         where
             E: serde::de::Error,
         {
-            u16::try_from(v).map_err(|_| E::custom("number out of range for u16"))
+            T::try_from(v)
+                .map_err(|_| E::custom(format_args!("number out of range for u{}", T::BITS)))
         }
-        fn visit_str<E>(self, v: &str) -> Result<u16, E>
+        fn visit_i64<E>(self, v: i64) -> Result<T, E>
         where
             E: serde::de::Error,
         {
-            let s = v.trim();
-            let s = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")).unwrap_or(s);
-            u16::from_str_radix(s, 16).map_err(|_| E::custom("invalid hex u16"))
+            let v = u64::try_from(v)
+                .map_err(|_| E::custom(format_args!("number out of range for u{}", T::BITS)))?;
+            self.visit_u64(v)
         }
-        fn visit_string<E>(self, v: String) -> Result<u16, E>
+        fn visit_str<E>(self, v: &str) -> Result<T, E>
         where
             E: serde::de::Error,
         {
-            self.visit_str(&v)
+            let s = v.trim();
+            let s = s
+                .strip_prefix("0x")
+                .or_else(|| s.strip_prefix("0X"))
+                .unwrap_or(s);
+            T::from_hex(s).map_err(|e| match e.kind() {
+                IntErrorKind::PosOverflow => {
+                    E::custom(format_args!("hex value out of range for u{}", T::BITS))
+                }
+                _ => E::custom(format_args!("invalid hex u{}", T::BITS)),
+            })
+        }
+    }
+    deserializer.deserialize_any(HexVisitor(PhantomData))
+}
+
+/// [`deserialize_hex`] for `u16`.
+pub fn deserialize_hex_u16<'de, D>(deserializer: D) -> Result<u16, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    deserialize_hex(deserializer)
+}
+
+/// A team task action reachable through `/authorize`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Task {
+    /// `task_camera`.
+    Camera,
+}
+
+impl Task {
+    /// Maps a lowercase COSMOS packet name to the task that authorizes it.
+    ///
+    /// Supported packet names:
+    /// - `noop`, `small_image`: [`Task::Camera`]
+    ///
+    /// Routing a new packet name is a single match arm here.
+    pub fn for_packet(packet_name: &str) -> Option<Self> {
+        match packet_name {
+            "noop" | "small_image" => Some(Self::Camera),
+            _ => None,
+        }
+    }
+
+    /// Issues the task to `peer`, returning the serialized command.
+    pub async fn issue(
+        self,
+        team: &Team<'_>,
+        task_name: Text,
+        peer: DeviceId,
+    ) -> aranya_client::Result<Box<[u8]>> {
+        match self {
+            Self::Camera => team.task_camera(task_name, peer).await,
         }
     }
-    deserializer.deserialize_any(HexVisitor)
 }
 
-pub async fn handle_post(State(state): State<AppState>, Json(body): Json<CMDSummary>) -> Response {
-    // Minimal echo; extend to use `ClientCtx` if needed.
+/// A JSON error response: `{ "error": "<message>", "code": "<CODE>" }`.
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    /// Stable, machine-readable error code.
+    pub code: &'static str,
+    /// Human-readable description.
+    pub message: String,
+    /// Sent as a `Retry-After` header, rounded up to whole seconds.
+    pub retry_after: Option<Duration>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    pub fn with_retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = Some(retry_after);
+        self
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = json!({ "error": self.message, "code": self.code });
+        let mut resp = (self.status, Json(body)).into_response();
+        if let Some(retry_after) = self.retry_after {
+            let secs = retry_after
+                .as_secs()
+                .saturating_add(u64::from(retry_after.subsec_nanos() > 0));
+            resp.headers_mut().insert(RETRY_AFTER, secs.into());
+        }
+        resp
+    }
+}
+
+pub async fn handle_post(
+    State(state): State<AppState>,
+    subject: Option<Extension<auth::AuthSubject>>,
+    headers: HeaderMap,
+    body: Result<Json<CMDSummary>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    info!("received POST /authorize");
+    let Json(body) =
+        body.map_err(|e| ApiError::new(e.status(), "INVALID_REQUEST", e.body_text()))?;
+    authorize(state, subject.map(|Extension(s)| s), headers, body).await
+}
+
+/// `GET /authorize` for dispatchers that cannot issue POST requests.
+///
+/// Reads the [`CMDSummary`] fields from query parameters. Query strings are
+/// commonly logged by proxies, so this must not carry secrets.
+pub async fn handle_get(
+    State(state): State<AppState>,
+    subject: Option<Extension<auth::AuthSubject>>,
+    headers: HeaderMap,
+    body: Result<Query<CMDSummary>, QueryRejection>,
+) -> Result<Response, ApiError> {
+    info!("received GET /authorize");
+    let Query(body) =
+        body.map_err(|e| ApiError::new(e.status(), "INVALID_REQUEST", e.body_text()))?;
+    authorize(state, subject.map(|Extension(s)| s), headers, body).await
+}
+
+async fn authorize(
+    state: AppState,
+    subject: Option<auth::AuthSubject>,
+    headers: HeaderMap,
+    body: CMDSummary,
+) -> Result<Response, ApiError> {
     info!(
-        "received POST /authorize: keycloak_id={} target={} packet_name={} stream_id=0x{:04X} function_code={}",
-        &body.keycloak_id,
-        &body.target,
-        &body.packet_name,
-        body.stream_id,
-        body.function_code
+        "authorize: keycloak_id={} target={} packet_name={} stream_id=0x{:04X} function_code={}",
+        &body.keycloak_id, &body.target, &body.packet_name, body.stream_id, body.function_code
     );
 
-    let owner_team = state.owner.team(state.owner_team_id);
-    // TODO: make task lowercase
-    let task_name = Text::try_from(body.packet_name.clone()).unwrap_or_else(|_| {
-        Text::from_str("unknown").unwrap()
+    if state.auth.is_some() {
+        match subject {
+            Some(auth::AuthSubject(sub)) if sub == body.keycloak_id => {}
+            Some(auth::AuthSubject(sub)) => {
+                info!(
+                    "token subject {sub} does not match keycloak_id {}",
+                    body.keycloak_id
+                );
+                return Err(ApiError::new(
+                    StatusCode::FORBIDDEN,
+                    "KEYCLOAK_ID_MISMATCH",
+                    "keycloak_id does not match the bearer token",
+                ));
+            }
+            None => {
+                return Err(ApiError::new(
+                    StatusCode::UNAUTHORIZED,
+                    "UNAUTHENTICATED",
+                    "missing bearer token",
+                ));
+            }
+        }
+    }
+
+    if let Some(limiter) = &state.rate_limit {
+        if let Err(retry_after) = limiter.check(&body.keycloak_id) {
+            info!("rate limited keycloak_id={}", body.keycloak_id);
+            return Err(ApiError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "RATE_LIMITED",
+                "too many requests",
+            )
+            .with_retry_after(retry_after));
+        }
+    }
+
+    let Some(&target_id) = state.targets.get(&normalize_target(&body.target)) else {
+        info!("unknown target: {}", body.target);
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "UNKNOWN_TARGET",
+            format!("unknown target: {}", body.target),
+        ));
+    };
+
+    let deadline = request_deadline(&headers, state.request_timeout)
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, "INVALID_DEADLINE", e.to_string()))?;
+    info!(?deadline, "effective request deadline");
+
+    let packet_name = body.packet_name.trim().to_lowercase();
+    let Some(task) = Task::for_packet(&packet_name) else {
+        info!("unknown packet name: {}", body.packet_name);
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "UNKNOWN_PACKET",
+            format!("unknown packet name: {}", body.packet_name),
+        ));
+    };
+    let task_name = Text::try_from(packet_name).map_err(|e| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "INVALID_PACKET_NAME",
+            format!("invalid packet name: {e}"),
+        )
+    })?;
+
+    let team_id = state.owner_team_id;
+    info!("issuing {task:?} on team {team_id} to target client id: {target_id}");
+    let issue = state.owner.with_retry(|owner| {
+        let task_name = task_name.clone();
+        async move { task.issue(&owner.team(team_id), task_name, target_id).await }
     });
+    let serialized_cmd = match timeout(deadline, issue).await {
+        Ok(Ok(serialized_cmd)) => serialized_cmd,
+        Ok(Err(e)) => {
+            info!("{task:?} failed: {e:#}");
+            return Err(ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "TASK_FAILED",
+                "failed to produce command bytes",
+            ));
+        }
+        Err(_) => {
+            info!("{task:?} exceeded deadline of {deadline:?}");
+            return Err(ApiError::new(
+                StatusCode::GATEWAY_TIMEOUT,
+                "DEADLINE_EXCEEDED",
+                format!("deadline of {}ms exceeded", deadline.as_millis()),
+            ));
+        }
+    };
+    info!("serialized_cmd produced: {} bytes", serialized_cmd.len());
+
+    let Some(key) = &state.envelope_key else {
+        return Ok((
+            StatusCode::OK,
+            [(CONTENT_TYPE, "application/octet-stream")],
+            serialized_cmd,
+        )
+            .into_response());
+    };
+    let mut nonce = [0u8; envelope::NONCE_LEN];
+    state.owner.client().await.rand(&mut nonce).await;
+    let sealed = envelope::seal(key, &serialized_cmd, nonce).map_err(|e| {
+        info!("unable to seal command envelope: {e:#}");
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "ENVELOPE_FAILED",
+            "failed to sign command",
+        )
+    })?;
+    Ok((StatusCode::OK, Json(sealed)).into_response())
+}
+
+/// `GET /health`: the process is up.
+pub async fn handle_health() -> StatusCode {
+    StatusCode::OK
+}
+
+/// `GET /ready`: the owner client can reach its daemon, reconnecting first if
+/// the connection broke.
+pub async fn handle_ready(State(state): State<AppState>) -> Result<Response, ApiError> {
+    let device_id = state.owner.ping(state.ready_timeout).await.map_err(|e| {
+        info!("readiness check failed: {e:#}");
+        ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "NOT_READY", e.to_string())
+    })?;
+    let body = json!({
+        "status": "ready",
+        "team_id": state.owner_team_id.to_string(),
+        "device_id": device_id.to_string(),
+    });
+    Ok((StatusCode::OK, Json(body)).into_response())
+}
+
+/// Builds the REST router, optionally nested under `base_path` (e.g.
+/// `/cosmos-gate`) for deployments behind a path-routing reverse proxy.
+pub fn build_router(state: AppState, base_path: Option<&str>) -> Router {
+    let mut router = Router::new().route("/authorize", post(handle_post).get(handle_get));
+    if let Some(keycloak) = state.auth.clone() {
+        router = router.route_layer(middleware::from_fn_with_state(
+            keycloak,
+            auth::require_bearer,
+        ));
+    }
+    let router = router
+        .route("/health", get(handle_health))
+        .route("/ready", get(handle_ready))
+        .with_state(state);
+    match base_path
+        .map(|p| p.trim_matches('/'))
+        .filter(|p| !p.is_empty())
+    {
+        Some(prefix) => Router::new().nest(&format!("/{prefix}"), router),
+        None => router,
+    }
+}
+
+/// Reports whether `device_id` is among `devices`, as returned by a
+/// `devices_on_team` query.
+pub fn is_device_on_team<'a>(
+    devices: impl IntoIterator<Item = &'a DeviceId>,
+    device_id: DeviceId,
+) -> bool {
+    devices.into_iter().any(|id| id.__id == device_id.__id)
+}
+
+/// Adds a device to `team`, treating "already a member" as success so that
+/// re-running provisioning after a partial failure converges.
+///
+/// Returns `false` if the device was already on the team.
+pub async fn add_device_idempotent(
+    team: &Team<'_>,
+    keys: KeyBundle,
+    device_id: DeviceId,
+) -> Result<bool> {
+    let devices = team
+        .queries()
+        .devices_on_team()
+        .await
+        .context("unable to query devices on team")?;
+    if is_device_on_team(devices.iter(), device_id) {
+        debug!(%device_id, "device already on team");
+        return Ok(false);
+    }
+    team.add_device_to_team(keys)
+        .await
+        .context("unable to add device to team")?;
+    Ok(true)
+}
+
+/// Adds `team` to `client`'s daemon, treating "already added" as success so
+/// that re-running provisioning after a partial failure converges.
+///
+/// Returns `false` if the daemon already had the team.
+pub async fn add_team_idempotent(client: &Client, cfg: AddTeamConfig) -> Result<bool> {
+    match client.add_team(cfg).await {
+        Ok(_) => Ok(true),
+        Err(err) if is_already_exists(&err) => {
+            debug!("team already added");
+            Ok(false)
+        }
+        Err(err) => Err(err).context("unable to add team"),
+    }
+}
 
-    // Simplify: use persisted member id instead of a live client
-    info!("owner_id: {}, owner_team_id: {}", state.owner.get_device_id().await.unwrap(), state.owner_team_id);
-    info!("issuing task_camera to target client id: {}", state.target_member_id);
+/// Reports whether `err` is the daemon refusing to store something it
+/// already has, such as a team's PSK seed.
+///
+/// The daemon only reports this as text, so this matches on the message.
+fn is_already_exists(err: &aranya_client::Error) -> bool {
+    match err {
+        aranya_client::Error::Aranya(err) => err.to_string().contains("already exists"),
+        _ => false,
+    }
+}
+
+/// How often [`wait_for_device_on_team`] polls.
+const DEVICE_POLL_INTERVAL: Duration = Duration::from_millis(50);
 
-    match owner_team.task_camera(task_name, state.target_member_id).await {
-        Ok(serialized_cmd) => {
-            info!("serialized_cmd produced: {} bytes", serialized_cmd.len());
-            (StatusCode::OK, [(CONTENT_TYPE, "application/octet-stream")], serialized_cmd)
-                .into_response()
+/// How long onboarding waits for the member to see itself on the team.
+pub const ONBOARD_SYNC_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Polls `team` until `device_id` is on it, or errors after `timeout`.
+///
+/// Useful after a sync to wait until the synced state is visible locally.
+pub async fn wait_for_device_on_team(
+    team: &Team<'_>,
+    device_id: DeviceId,
+    timeout: Duration,
+) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match team.queries().devices_on_team().await {
+            Ok(devices) if is_device_on_team(devices.iter(), device_id) => return Ok(()),
+            Ok(_) => {}
+            Err(err) => debug!("devices_on_team query failed: {err}"),
         }
-        Err(e) => {
-            info!("task_camera failed: {e}");
-            (StatusCode::INTERNAL_SERVER_ERROR, "failed to produce command bytes".to_string())
-                .into_response()
+        if Instant::now() >= deadline {
+            bail!(
+                "device {device_id} did not appear on team {} within {timeout:?}",
+                team.team_id()
+            );
         }
+        sleep(DEVICE_POLL_INTERVAL).await;
+    }
+}
+
+/// Verifies that every target routes to a member in `members`, so that a
+/// targets file edited by hand (or left over from another onboarding) is
+/// caught at startup.
+pub fn verify_targets_in_roster(
+    targets: &HashMap<String, DeviceId>,
+    members: &[MemberRecord],
+) -> Result<()> {
+    for (target, &id) in targets {
+        if !is_device_on_team(members.iter().map(|m| &m.device_id), id) {
+            bail!("target {target} routes to {id}, which is not an onboarded member; re-run the init binary");
+        }
+    }
+    Ok(())
+}
+
+/// Verifies that `target_member_id` is on the owner's team, so that stale
+/// state files are caught at startup rather than on the first request.
+pub async fn verify_target_on_team(
+    owner: &Client,
+    team_id: TeamId,
+    target_member_id: DeviceId,
+) -> Result<()> {
+    let devices = owner
+        .team(team_id)
+        .queries()
+        .devices_on_team()
+        .await
+        .context("unable to query devices on team")?;
+    if !is_device_on_team(devices.iter(), target_member_id) {
+        bail!("target member {target_member_id} is not on team {team_id}; re-run the init binary");
+    }
+    Ok(())
+}
+
+/// Re-assigns `owner`'s AQC network identifier to its current AQC server
+/// address and records it in `path`.
+///
+/// Each daemon start binds the AQC server to a new ephemeral port, so the
+/// identifier assigned during onboarding is stale once the init binary
+/// exits. Whoever runs a member's daemon must likewise have its identifier
+/// re-assigned after every start.
+pub async fn refresh_owner_net_identifier(
+    owner: &ClientCtx,
+    team_id: TeamId,
+    path: &Path,
+) -> Result<String> {
+    let net_id = owner.aqc_local_addr()?.to_string();
+    owner
+        .client
+        .team(team_id)
+        .assign_aqc_net_identifier(owner.id, net_id.parse::<NetIdentifier>()?)
+        .await
+        .context("unable to assign owner AQC net identifier")?;
+    write_atomic(path, &net_id).await?;
+    info!(owner = %net_id, "assigned owner AQC net identifier");
+    Ok(net_id)
+}
+
+/// The files onboarding writes to the owner's work dir.
+#[derive(Clone, Debug)]
+pub struct StateFiles {
+    pub init_marker: PathBuf,
+    pub team_id: PathBuf,
+    pub member_id: PathBuf,
+    pub owner_net_id: PathBuf,
+    pub targets: PathBuf,
+    pub members: PathBuf,
+}
+
+impl StateFiles {
+    pub fn new(owner_dir: &Path) -> Self {
+        Self {
+            init_marker: init_marker_path(owner_dir),
+            team_id: team_id_path(owner_dir),
+            member_id: member_id_path(owner_dir),
+            owner_net_id: owner_net_id_path(owner_dir),
+            targets: targets_path(owner_dir),
+            members: members_path(owner_dir),
+        }
+    }
+
+    /// Reports whether onboarding has completed.
+    pub async fn is_initialized(&self) -> bool {
+        fs::metadata(&self.init_marker).await.is_ok()
+    }
+}
+
+/// Default interval at which onboarded peers sync.
+pub const DEFAULT_SYNC_INTERVAL: Duration = Duration::from_millis(400);
+
+/// Shortest accepted sync interval; anything shorter busy-syncs.
+pub const MIN_SYNC_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Returns `interval` if it is at least [`MIN_SYNC_INTERVAL`].
+pub fn validate_sync_interval(interval: Duration) -> Result<Duration> {
+    if interval < MIN_SYNC_INTERVAL {
+        bail!(
+            "sync interval {}ms is below the minimum of {}ms",
+            interval.as_millis(),
+            MIN_SYNC_INTERVAL.as_millis()
+        );
+    }
+    Ok(interval)
+}
+
+/// A team created by [`Onboarder::create_team`].
+#[derive(Clone)]
+pub struct CreatedTeam {
+    pub team_id: TeamId,
+    /// QUIC sync PSK seed shared with members.
+    seed_ikm: [u8; 32],
+}
+
+impl std::fmt::Debug for CreatedTeam {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Omit `seed_ikm`; it is secret.
+        f.debug_struct("CreatedTeam")
+            .field("team_id", &self.team_id)
+            .finish_non_exhaustive()
     }
 }
 
-pub fn build_router(state: AppState) -> Router {
-    Router::new().route("/authorize", post(handle_post)).with_state(state)
+/// AQC network identifiers assigned by [`Onboarder::assign_net_identifiers`].
+#[derive(Clone, Debug)]
+pub struct AqcNetIds {
+    pub owner: String,
+    pub member: String,
 }
 
+/// Onboards `member` onto a team owned by `owner`, one step at a time.
+///
+/// [`initialize_or_return`] runs the steps in order; callers that need more
+/// control can run them individually.
+#[derive(Copy, Clone)]
+pub struct Onboarder<'a> {
+    pub owner: &'a ClientCtx,
+    pub member: &'a ClientCtx,
+}
+
+impl<'a> Onboarder<'a> {
+    pub fn new(owner: &'a ClientCtx, member: &'a ClientCtx) -> Self {
+        Self { owner, member }
+    }
+
+    /// Creates a new team on the owner.
+    pub async fn create_team(&self) -> Result<CreatedTeam> {
+        let mut seed_ikm = [0u8; 32];
+        self.owner.client.rand(&mut seed_ikm).await;
+        let owner_cfg = {
+            let qs_cfg = CreateTeamQuicSyncConfig::builder()
+                .seed_ikm(seed_ikm)
+                .build()?;
+            CreateTeamConfig::builder().quic_sync(qs_cfg).build()?
+        };
+        let team_id = self
+            .owner
+            .client
+            .create_team(owner_cfg)
+            .await
+            .context("create team")?
+            .team_id();
+        info!(%team_id, "team created");
+        Ok(CreatedTeam { team_id, seed_ikm })
+    }
+
+    /// Adds the team to the member's daemon and the member to the team.
+    ///
+    /// Returns `false` if the member was already on the team.
+    pub async fn add_member(&self, team: &CreatedTeam) -> Result<bool> {
+        let add_team_cfg = {
+            let qs_cfg = AddTeamQuicSyncConfig::builder()
+                .seed_ikm(team.seed_ikm)
+                .build()?;
+            AddTeamConfig::builder()
+                .quic_sync(qs_cfg)
+                .team_id(team.team_id)
+                .build()?
+        };
+        add_team_idempotent(&self.member.client, add_team_cfg)
+            .await
+            .context("unable to add team to member")?;
+        let owner_team = self.owner.client.team(team.team_id);
+        let added =
+            add_device_idempotent(&owner_team, self.member.pk.clone(), self.member.id).await?;
+        info!(added, "member added to team");
+        Ok(added)
+    }
+
+    /// Makes the owner and member sync with each other every `interval`,
+    /// then syncs the member once so it sees the team immediately.
+    ///
+    /// `interval` must be at least [`MIN_SYNC_INTERVAL`].
+    pub async fn setup_sync(&self, team_id: TeamId, interval: Duration) -> Result<()> {
+        let interval = validate_sync_interval(interval)?;
+        let sync_cfg = SyncPeerConfig::builder().interval(interval).build()?;
+        let owner_addr = self.owner.aranya_local_addr().await?;
+        let member_addr = self.member.aranya_local_addr().await?;
+        let owner_team = self.owner.client.team(team_id);
+        let member_team = self.member.client.team(team_id);
+        owner_team
+            .add_sync_peer(member_addr.into(), sync_cfg.clone())
+            .await?;
+        member_team
+            .add_sync_peer(owner_addr.into(), sync_cfg)
+            .await?;
+
+        // One way to make sure member receives the team info is to trigger a sync from member to owner.
+        member_team.sync_now(owner_addr.into(), None).await?;
+        Ok(())
+    }
+
+    /// Assigns the owner's and member's AQC server addresses as their AQC
+    /// network identifiers so that they can open channels to each other.
+    ///
+    /// The addresses are only valid while these daemons run; see
+    /// [`refresh_owner_net_identifier`].
+    pub async fn assign_net_identifiers(&self, team_id: TeamId) -> Result<AqcNetIds> {
+        let owner_team = self.owner.client.team(team_id);
+        let net_ids = AqcNetIds {
+            owner: self.owner.aqc_local_addr()?.to_string(),
+            member: self.member.aqc_local_addr()?.to_string(),
+        };
+        for (ctx, net_id) in [(self.owner, &net_ids.owner), (self.member, &net_ids.member)] {
+            owner_team
+                .assign_aqc_net_identifier(ctx.id, net_id.parse::<NetIdentifier>()?)
+                .await
+                .with_context(|| format!("unable to assign AQC net identifier to {}", ctx.name))?;
+        }
+        info!(owner = %net_ids.owner, member = %net_ids.member, "assigned AQC net identifiers");
+        Ok(net_ids)
+    }
+
+    /// Persists the team and member ids and AQC net identifiers, routing
+    /// `target` to the member, then marks initialization complete.
+    ///
+    /// The marker is written last so a reader that sees it also sees the
+    /// other files.
+    pub async fn persist_markers(
+        &self,
+        files: &StateFiles,
+        team_id: TeamId,
+        net_ids: &AqcNetIds,
+        target: &str,
+    ) -> Result<()> {
+        write_atomic(&files.team_id, team_id.to_string()).await?;
+        write_atomic(&files.member_id, self.member.id.to_string()).await?;
+        write_atomic(&files.owner_net_id, &net_ids.owner).await?;
+        write_targets(
+            &files.targets,
+            &HashMap::from([(target.to_owned(), self.member.id)]),
+        )
+        .await?;
+        append_member(
+            &files.members,
+            MemberRecord {
+                name: self.member.name.clone(),
+                device_id: self.member.id,
+                aqc_net_id: Some(net_ids.member.clone()),
+            },
+        )
+        .await?;
+        write_atomic(&files.init_marker, b"initialized").await?;
+        info!("wrote init marker, team_id, member_id, owner net id, targets, and members files");
+        Ok(())
+    }
+}
+
+/// Onboards `member` onto a new team owned by `owner` and persists the
+/// result, or, if `files` shows onboarding already happened, returns the
+/// persisted team id.
 pub async fn initialize_or_return(
     owner: &ClientCtx,
-    _member: &ClientCtx,
-    init_marker: &Path,
-    team_id_path: &Path,
-    // NEW ARG: path to persist member_id
-    member_id_path: &Path,
-    already_initialized: bool,
+    member: &ClientCtx,
+    files: &StateFiles,
+    // COSMOS target name routed to the member.
+    target: &str,
+    sync_interval: Duration,
 ) -> Result<TeamId> {
-    if already_initialized {
+    if files.is_initialized().await {
         info!("already initialized; skipping onboarding");
-        let team_id = read_team_id(team_id_path).await?;
-        let member_id = read_member_id(member_id_path).await?;
+        let team_id = read_team_id(&files.team_id).await?;
+        let member_id = read_member_id(&files.member_id).await?;
         info!(%team_id, "read team_id from file");
         info!("member id: {}", member_id);
         info!("owner id: {}", owner.id);
         return Ok(team_id);
     }
 
-    // Create team on owner.
     info!("creating team (first-time onboarding)");
-    let seed_ikm = {
-        let mut buf = [0u8; 32];
-        owner.client.rand(&mut buf).await;
-        buf
-    };
-    let owner_cfg = {
-        let qs_cfg = CreateTeamQuicSyncConfig::builder()
-            .seed_ikm(seed_ikm)
-            .build()?;
-        CreateTeamConfig::builder().quic_sync(qs_cfg).build()?
+    let onboarder = Onboarder::new(owner, member);
+    let team = onboarder.create_team().await?;
+    onboarder.add_member(&team).await?;
+    onboarder.setup_sync(team.team_id, sync_interval).await?;
+    wait_for_device_on_team(
+        &member.client.team(team.team_id),
+        member.id,
+        ONBOARD_SYNC_TIMEOUT,
+    )
+    .await
+    .context("member did not receive the team state")?;
+    let net_ids = onboarder.assign_net_identifiers(team.team_id).await?;
+    info!("onboarding complete");
+    onboarder
+        .persist_markers(files, team.team_id, &net_ids, target)
+        .await?;
+    Ok(team.team_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use aranya_daemon::{
+        config::{self as daemon_cfg, Config, Toggle},
+        Daemon as InProcessDaemon, DaemonHandle,
     };
-    let owner_team = owner
-        .client
-        .create_team(owner_cfg)
-        .await
-        .context("create team")?;
-    let team_id = owner_team.team_id();
-    info!(%team_id, "team created");
-
-    // Onboard member.
-    let add_team_cfg = {
-        let qs_cfg = AddTeamQuicSyncConfig::builder()
-            .seed_ikm(seed_ikm)
-            .build()?;
-        AddTeamConfig::builder()
-            .quic_sync(qs_cfg)
+    use aranya_daemon_api::shm;
+    use aranya_util::Addr;
+    use axum::{body::Body, http::Request};
+    use jsonwebtoken::Algorithm;
+    use tempfile::tempdir;
+    use tower::ServiceExt as _;
+
+    use super::*;
+
+    /// Runs a daemon named `name` in this process, with its state under
+    /// `dir`, and connects a client to it.
+    async fn spawn_test_daemon(dir: &Path, name: &str) -> (DaemonHandle, Client) {
+        let work_dir = dir.join(name);
+        let any_addr = Addr::from((std::net::Ipv4Addr::LOCALHOST, 0));
+        let shm_path: Box<shm::Path> = format!("/{}\0", instance_name(name))
+            .as_str()
+            .try_into()
+            .unwrap();
+        let cfg = Config {
+            name: name.into(),
+            runtime_dir: work_dir.join("run"),
+            state_dir: work_dir.join("state"),
+            cache_dir: work_dir.join("cache"),
+            logs_dir: work_dir.join("logs"),
+            config_dir: work_dir.join("config"),
+            aqc: Toggle::Enabled(daemon_cfg::AqcConfig {}),
+            afc: Toggle::Enabled(daemon_cfg::AfcConfig {
+                shm_path,
+                max_chans: 100,
+            }),
+            sync: daemon_cfg::SyncConfig {
+                quic: Toggle::Enabled(daemon_cfg::QuicSyncConfig { addr: any_addr }),
+            },
+        };
+        for dir in [
+            &cfg.runtime_dir,
+            &cfg.state_dir,
+            &cfg.cache_dir,
+            &cfg.logs_dir,
+            &cfg.config_dir,
+        ] {
+            fs::create_dir_all(dir).await.unwrap();
+        }
+        let uds_path = cfg.uds_api_sock();
+        let daemon = InProcessDaemon::load(cfg)
+            .await
+            .unwrap()
+            .spawn()
+            .await
+            .unwrap();
+        let client = connect_client(&uds_path, &any_addr).await.unwrap();
+        (daemon, client)
+    }
+
+    /// Runs an owner daemon under `dir` and returns gate state for it with
+    /// no targets and the team id `team_id`.
+    async fn test_app_state(dir: &Path, team_id: TeamId) -> (DaemonHandle, AppState) {
+        let (daemon, client) = spawn_test_daemon(dir, "owner").await;
+        let owner = reconnect::ReconnectingClient::new(
+            Arc::new(client),
+            uds_path(&dir.join("owner")),
+            Addr::from((std::net::Ipv4Addr::LOCALHOST, 0)),
+        );
+        let state = AppState {
+            owner: Arc::new(owner),
+            owner_team_id: team_id,
+            targets: Arc::new(HashMap::new()),
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            envelope_key: None,
+            auth: None,
+            rate_limit: None,
+        };
+        (daemon, state)
+    }
+
+    /// Sends `req` to `app`, returning the status and the error `code`, if
+    /// any.
+    async fn call(app: &Router, req: Request<Body>) -> (StatusCode, String) {
+        let resp = app.clone().oneshot(req).await.unwrap();
+        let status = resp.status();
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let code = serde_json::from_slice::<serde_json::Value>(&body)
+            .ok()
+            .and_then(|body| body["code"].as_str().map(str::to_owned))
+            .unwrap_or_default();
+        (status, code)
+    }
+
+    #[tokio::test]
+    async fn test_handle_get_query() {
+        let dir = tempdir().unwrap();
+        let team_id = "11111111111111111111111111111111"
+            .parse::<TeamId>()
+            .unwrap();
+        let (_daemon, state) = test_app_state(dir.path(), team_id).await;
+        let app = build_router(state, None);
+        let get = |query: &str| {
+            let req = Request::get(format!("/authorize?{query}"))
+                .body(Body::empty())
+                .unwrap();
+            call(&app, req)
+        };
+
+        // Well-formed queries parse, with `stream_id` in hex with or without
+        // `0x`, and fail later on the unknown target.
+        for stream_id in ["0x1A2B", "1a2b"] {
+            let (status, code) = get(&format!(
+                "keycloak_id=k&target=INST&packet_name=noop&stream_id={stream_id}&function_code=7"
+            ))
+            .await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            assert_eq!(code, "UNKNOWN_TARGET");
+        }
+
+        for query in [
+            // Missing `stream_id`.
+            "keycloak_id=k&target=INST&packet_name=noop&function_code=7",
+            // Not hex.
+            "keycloak_id=k&target=INST&packet_name=noop&stream_id=0xZZ&function_code=7",
+            // Too wide for u16.
+            "keycloak_id=k&target=INST&packet_name=noop&stream_id=0x10000&function_code=7",
+            // Not a number.
+            "keycloak_id=k&target=INST&packet_name=noop&stream_id=0x1A&function_code=seven",
+        ] {
+            let (status, code) = get(query).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{query}");
+            assert_eq!(code, "INVALID_REQUEST", "{query}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handle_ready() {
+        let dir = tempdir().unwrap();
+        let team_id = "11111111111111111111111111111111"
+            .parse::<TeamId>()
+            .unwrap();
+        let (_daemon, state) = test_app_state(dir.path(), team_id).await;
+        let app = build_router(state, None);
+
+        let req = Request::get("/ready").body(Body::empty()).unwrap();
+        assert_eq!(call(&app, req).await, (StatusCode::OK, String::new()));
+    }
+
+    #[tokio::test]
+    async fn test_authorize_subject() {
+        let dir = tempdir().unwrap();
+        let team_id = "11111111111111111111111111111111"
+            .parse::<TeamId>()
+            .unwrap();
+        let (_daemon, mut state) = test_app_state(dir.path(), team_id).await;
+        state.auth = Some(Arc::new(auth::KeycloakAuth::new(
+            auth::tests::serve_jwks("RS256").await,
+            auth::tests::TEST_AUDIENCE,
+        )));
+        let app = build_router(state, None);
+        let token = auth::tests::sign(Algorithm::RS256, &auth::tests::claims("alice"));
+
+        let cases = [
+            (None, "alice", StatusCode::UNAUTHORIZED, "UNAUTHENTICATED"),
+            (
+                Some("not-a-token"),
+                "alice",
+                StatusCode::UNAUTHORIZED,
+                "UNAUTHENTICATED",
+            ),
+            (
+                Some(token.as_str()),
+                "bob",
+                StatusCode::FORBIDDEN,
+                "KEYCLOAK_ID_MISMATCH",
+            ),
+            // Authenticated; fails later on the unknown target.
+            (
+                Some(token.as_str()),
+                "alice",
+                StatusCode::NOT_FOUND,
+                "UNKNOWN_TARGET",
+            ),
+        ];
+        for (token, keycloak_id, want_status, want_code) in cases {
+            let summary = format!(
+                "keycloak_id={keycloak_id}&target=INST&packet_name=noop&stream_id=0x1A&function_code=7"
+            );
+            let post = Request::post("/authorize")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    json!({
+                        "keycloak_id": keycloak_id,
+                        "target": "INST",
+                        "packet_name": "noop",
+                        "stream_id": "0x1A",
+                        "function_code": 7,
+                    })
+                    .to_string(),
+                ));
+            let get = Request::get(format!("/authorize?{summary}")).body(Body::empty());
+            for req in [post, get] {
+                let mut req = req.unwrap();
+                if let Some(token) = token {
+                    req.headers_mut().insert(
+                        axum::http::header::AUTHORIZATION,
+                        format!("Bearer {token}").parse().unwrap(),
+                    );
+                }
+                let method = req.method().clone();
+                let (status, code) = call(&app, req).await;
+                assert_eq!(status, want_status, "{method} {keycloak_id} {token:?}");
+                assert_eq!(code, want_code, "{method} {keycloak_id} {token:?}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_add_member_twice() {
+        let dir = tempdir().unwrap();
+        let (_owner_daemon, owner) = spawn_test_daemon(dir.path(), "owner").await;
+        let (_member_daemon, member) = spawn_test_daemon(dir.path(), "member").await;
+        let mut seed_ikm = [0u8; 32];
+        owner.rand(&mut seed_ikm).await;
+        let owner_cfg = CreateTeamConfig::builder()
+            .quic_sync(
+                CreateTeamQuicSyncConfig::builder()
+                    .seed_ikm(seed_ikm)
+                    .build()
+                    .unwrap(),
+            )
+            .build()
+            .unwrap();
+        let team_id = owner.create_team(owner_cfg).await.unwrap().team_id();
+        let add_team_cfg = AddTeamConfig::builder()
+            .quic_sync(
+                AddTeamQuicSyncConfig::builder()
+                    .seed_ikm(seed_ikm)
+                    .build()
+                    .unwrap(),
+            )
             .team_id(team_id)
-            .build()?
-    };
-    let member_team = _member.client.add_team(add_team_cfg).await?;
-    owner_team.add_device_to_team(_member.pk.clone()).await?;
-    info!("member added to team");
-
-    // Setup sync peers.
-    let sync_interval = Duration::from_millis(400);
-    let sync_cfg = SyncPeerConfig::builder().interval(sync_interval).build()?;
-    let owner_addr = owner.aranya_local_addr().await?;
-    let member_addr = _member.aranya_local_addr().await?;
-    owner_team
-        .add_sync_peer((member_addr).into(), sync_cfg.clone())
-        .await?;
-    member_team
-        .add_sync_peer((owner_addr).into(), sync_cfg.clone())
-        .await?;
+            .build()
+            .unwrap();
+        let pk = member.get_key_bundle().await.unwrap();
+        let id = member.get_device_id().await.unwrap();
+        let owner_team = owner.team(team_id);
 
-    // One way to make sure member receives the team info is to trigger a sync from member to owner.
-    member_team.sync_now(member_addr.into(), None).await?;
+        // The first pass adds; a re-run after a partial failure is a no-op.
+        for added in [true, false] {
+            assert_eq!(
+                add_team_idempotent(&member, add_team_cfg.clone())
+                    .await
+                    .unwrap(),
+                added
+            );
+            assert_eq!(
+                add_device_idempotent(&owner_team, pk.clone(), id)
+                    .await
+                    .unwrap(),
+                added
+            );
+        }
+        let devices = owner_team.queries().devices_on_team().await.unwrap();
+        assert_eq!(devices.iter().filter(|d| d.__id == id.__id).count(), 1);
+    }
 
-    info!("onboarding complete");
+    #[test]
+    fn test_task_for_packet() {
+        assert_eq!(Task::for_packet("noop"), Some(Task::Camera));
+        assert_eq!(Task::for_packet("small_image"), Some(Task::Camera));
+        assert_eq!(Task::for_packet("SMALL_IMAGE"), None);
+        assert_eq!(Task::for_packet("self_destruct"), None);
+    }
+
+    #[test]
+    fn test_request_deadline_clamped() {
+        let limit = Duration::from_secs(10);
+        let deadline = |v: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(DEADLINE_HEADER, v.parse().unwrap());
+            request_deadline(&headers, limit)
+        };
+        assert_eq!(request_deadline(&HeaderMap::new(), limit).unwrap(), limit);
+        assert_eq!(deadline("500").unwrap(), Duration::from_millis(500));
+        assert_eq!(deadline("3600000").unwrap(), limit);
+        assert!(deadline("soon").is_err());
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Hex<T: HexInt>(#[serde(deserialize_with = "deserialize_hex")] T);
+
+    fn hex<T: HexInt>(v: serde_json::Value) -> Result<T, serde_json::Error> {
+        serde_json::from_value::<Hex<T>>(v).map(|Hex(v)| v)
+    }
+
+    #[test]
+    fn test_deserialize_hex_u8() {
+        assert_eq!(hex::<u8>(json!("0xFF")).unwrap(), 0xFF);
+        assert_eq!(hex::<u8>(json!("1a")).unwrap(), 0x1A);
+        assert_eq!(hex::<u8>(json!(200)).unwrap(), 200);
+        assert!(hex::<u8>(json!("0x100")).is_err());
+        assert!(hex::<u8>(json!(256)).is_err());
+        assert!(hex::<u8>(json!(-1)).is_err());
+    }
+
+    #[test]
+    fn test_deserialize_hex_u16() {
+        assert_eq!(hex::<u16>(json!("0x1A2B")).unwrap(), 0x1A2B);
+        assert_eq!(hex::<u16>(json!(" 0XFFFF ")).unwrap(), 0xFFFF);
+        assert_eq!(hex::<u16>(json!(65535)).unwrap(), 65535);
+        assert!(hex::<u16>(json!("0x10000")).is_err());
+        assert!(hex::<u16>(json!(65536)).is_err());
+        assert!(hex::<u16>(json!("0xZZ")).is_err());
+        assert!(hex::<u16>(json!("")).is_err());
+
+        #[derive(Deserialize)]
+        struct Alias(#[serde(deserialize_with = "deserialize_hex_u16")] u16);
+        let Alias(v) = serde_json::from_value(json!("0x07FF")).unwrap();
+        assert_eq!(v, 0x07FF);
+    }
+
+    #[test]
+    fn test_deserialize_hex_u32() {
+        assert_eq!(hex::<u32>(json!("0xDEADBEEF")).unwrap(), 0xDEAD_BEEF);
+        assert_eq!(hex::<u32>(json!(4_294_967_295u64)).unwrap(), u32::MAX);
+        assert!(hex::<u32>(json!("0x100000000")).is_err());
+        assert!(hex::<u32>(json!(4_294_967_296u64)).is_err());
+    }
+
+    #[test]
+    fn test_deserialize_hex_u64() {
+        assert_eq!(hex::<u64>(json!("FFFFFFFFFFFFFFFF")).unwrap(), u64::MAX);
+        assert_eq!(hex::<u64>(json!(u64::MAX)).unwrap(), u64::MAX);
+        assert!(hex::<u64>(json!("0x10000000000000000")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_targets_round_trip() {
+        let dir = tempdir().unwrap();
+        let path = targets_path(dir.path());
+        let id = "11111111111111111111111111111111"
+            .parse::<DeviceId>()
+            .unwrap();
+        write_targets(&path, &HashMap::from([(" inst ".to_owned(), id)]))
+            .await
+            .unwrap();
+
+        let targets = read_targets(&path).await.unwrap();
+        assert_eq!(targets.len(), 1);
+        assert_eq!(
+            targets[&normalize_target("Inst")].to_string(),
+            id.to_string()
+        );
+    }
+
+    #[test]
+    fn test_verify_targets_in_roster() {
+        let a = "11111111111111111111111111111111"
+            .parse::<DeviceId>()
+            .unwrap();
+        let b = "22222222222222222222222222222222"
+            .parse::<DeviceId>()
+            .unwrap();
+        let members = [MemberRecord {
+            name: "member".into(),
+            device_id: a,
+            aqc_net_id: None,
+        }];
+        let targets = HashMap::from([("INST".to_owned(), a)]);
+        assert!(verify_targets_in_roster(&targets, &members).is_ok());
+
+        let targets = HashMap::from([("INST".to_owned(), a), ("INST2".to_owned(), b)]);
+        let err = verify_targets_in_roster(&targets, &members).unwrap_err();
+        assert!(err.to_string().contains("INST2"));
+    }
 
-    // Mark initialization complete.
-    fs::write(init_marker, b"initialized").await?;
-    fs::write(team_id_path, team_id.to_string()).await?;
-    // NEW: persist member id
-    fs::write(member_id_path, _member.id.to_string()).await?;
-    info!("wrote init marker, team_id, and member_id files");
+    #[test]
+    fn test_is_device_on_team() {
+        let a = "11111111111111111111111111111111"
+            .parse::<DeviceId>()
+            .unwrap();
+        let b = "22222222222222222222222222222222"
+            .parse::<DeviceId>()
+            .unwrap();
+        let mut devices = Vec::new();
+        assert!(!is_device_on_team(&devices, a));
 
-    Ok(team_id)
-}
\ No newline at end of file
+        // Once `a` is added, adding it again is skipped.
+        devices.push(a);
+        assert!(is_device_on_team(&devices, a));
+        assert!(!is_device_on_team(&devices, b));
+    }
+
+    #[tokio::test]
+    async fn test_members_roster() {
+        let dir = tempdir().unwrap();
+        let path = members_path(dir.path());
+        assert!(read_members(&path).await.unwrap().is_empty());
+
+        let a = "11111111111111111111111111111111"
+            .parse::<DeviceId>()
+            .unwrap();
+        let b = "22222222222222222222222222222222"
+            .parse::<DeviceId>()
+            .unwrap();
+        let record = |name: &str, device_id, aqc_net_id: Option<&str>| MemberRecord {
+            name: name.into(),
+            device_id,
+            aqc_net_id: aqc_net_id.map(Into::into),
+        };
+        append_member(&path, record("member", a, None))
+            .await
+            .unwrap();
+        append_member(&path, record("camera", b, Some("127.0.0.1:5050")))
+            .await
+            .unwrap();
+        // Re-onboarding `a` replaces its record.
+        append_member(&path, record("member", a, Some("127.0.0.1:6060")))
+            .await
+            .unwrap();
+
+        let members = read_members(&path).await.unwrap();
+        assert_eq!(members.len(), 2);
+        assert_eq!(members[0].name, "camera");
+        assert_eq!(members[0].device_id.to_string(), b.to_string());
+        assert_eq!(members[1].aqc_net_id.as_deref(), Some("127.0.0.1:6060"));
+    }
+
+    #[tokio::test]
+    async fn test_read_team_id_during_write() {
+        let dir = tempdir().unwrap();
+        let path = team_id_path(dir.path());
+        let team_id = "11111111111111111111111111111111"
+            .parse::<TeamId>()
+            .unwrap()
+            .to_string();
+        write_atomic(&path, &team_id).await.unwrap();
+
+        let writer = {
+            let path = path.clone();
+            let team_id = team_id.clone();
+            tokio::spawn(async move {
+                for _ in 0..100 {
+                    write_atomic(&path, &team_id).await.unwrap();
+                }
+            })
+        };
+        for _ in 0..100 {
+            let got = read_team_id(&path).await.unwrap();
+            assert_eq!(got.to_string(), team_id);
+        }
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_team_id_retries_torn_read() {
+        let dir = tempdir().unwrap();
+        let path = team_id_path(dir.path());
+        let team_id = "11111111111111111111111111111111"
+            .parse::<TeamId>()
+            .unwrap()
+            .to_string();
+
+        // Simulate a non-atomic writer that has truncated but not yet
+        // written the file.
+        fs::write(&path, "").await.unwrap();
+        let writer = {
+            let path = path.clone();
+            let team_id = team_id.clone();
+            tokio::spawn(async move {
+                sleep(Duration::from_millis(30)).await;
+                fs::write(&path, &team_id).await.unwrap();
+            })
+        };
+        let got = read_team_id(&path).await.unwrap();
+        assert_eq!(got.to_string(), team_id);
+        writer.await.unwrap();
+
+        fs::write(&path, "not an id").await.unwrap();
+        assert!(read_team_id(&path).await.is_err());
+    }
+}
@@ -1,12 +1,39 @@
 use std::{env, path::PathBuf};
-use anyhow::{Context as _, Result};
-use tracing_subscriber::{layer::SubscriberExt, prelude::*, util::SubscriberInitExt, EnvFilter};
 
+use anyhow::{bail, Context as _, Result};
 // Import from the local lib crate.
-use cosmos_gate::{ClientCtx, DaemonPath, initialize_or_return, init_marker_path, team_id_path, member_id_path};
+use cosmos_gate::{
+    config::{PartialConfig, ResolvedConfig, DEFAULT_LOG_LEVEL},
+    initialize_or_return, ClientCtx, StateFiles, DEFAULT_SHUTDOWN_GRACE,
+};
+use tracing_subscriber::{layer::SubscriberExt, prelude::*, util::SubscriberInitExt, EnvFilter};
 
+/// Args: [--sync-interval-ms=<ms>] [daemon_path] [owner_work_dir] [member_work_dir]
+///
+/// Missing args fall back to the environment and config file; see
+/// [`cosmos_gate::config`].
 #[tokio::main]
 async fn main() -> Result<()> {
+    let (flags, args): (Vec<_>, Vec<_>) =
+        env::args().skip(1).partition(|arg| arg.starts_with("--"));
+    let mut sync_interval_ms = None;
+    for flag in &flags {
+        match flag.split_once('=') {
+            Some(("--sync-interval-ms", ms)) => {
+                sync_interval_ms = Some(ms.parse().context("invalid --sync-interval-ms")?);
+            }
+            _ => bail!("unknown flag: {flag}"),
+        }
+    }
+    let mut args = args.into_iter();
+    let cfg = ResolvedConfig::load(PartialConfig {
+        daemon_path: args.next().map(PathBuf::from),
+        owner_dir: args.next().map(PathBuf::from),
+        member_dir: args.next().map(PathBuf::from),
+        sync_interval_ms,
+        ..Default::default()
+    })?;
+
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::fmt::layer()
@@ -14,42 +41,34 @@ async fn main() -> Result<()> {
                 .with_target(false)
                 .compact()
                 .with_filter(
-                    EnvFilter::try_from_env("COSMOS_GATE_LOG")
-                        .unwrap_or_else(|_| EnvFilter::new("info")),
+                    EnvFilter::try_new(&cfg.log_level)
+                        .unwrap_or_else(|_| EnvFilter::new(DEFAULT_LOG_LEVEL)),
                 ),
         )
         .init();
 
-    // Args: <daemon_path> <owner_work_dir> <member_work_dir>
-    let mut args = env::args();
-    let _exe = args.next();
-    let daemon_exe = args.next().context("missing <daemon_path>")?;
-    let owner_dir = args.next().context("missing <owner_work_dir>")?;
-    let member_dir = args.next().context("missing <member_work_dir>")?;
-
-    let daemon_path = DaemonPath(daemon_exe.into());
-    let owner_dir_pb = PathBuf::from(&owner_dir);
-    let member_dir_pb = PathBuf::from(&member_dir);
-
-    let init_marker = init_marker_path(&owner_dir_pb);
-    let team_id_path = team_id_path(&owner_dir_pb);
-    let member_id_path = member_id_path(&owner_dir_pb);
-    let already_initialized = tokio::fs::metadata(&init_marker).await.is_ok();
+    let daemon_path = cfg.daemon_path()?;
+    let owner_dir_pb = cfg.owner_dir()?;
+    let member_dir_pb = cfg.member_dir()?;
+    let sync_interval = cfg.sync_interval()?;
 
     // Spawn daemons and clients
-    let owner = ClientCtx::new("owner", &daemon_path, owner_dir_pb.clone()).await?;
-    let member = ClientCtx::new("member", &daemon_path, member_dir_pb.clone()).await?;
+    let owner =
+        ClientCtx::new("owner", &daemon_path, owner_dir_pb.clone(), cfg.daemon_logs).await?;
+    let member = ClientCtx::new(
+        "member",
+        &daemon_path,
+        member_dir_pb.clone(),
+        cfg.daemon_logs,
+    )
+    .await?;
 
     // Onboard (or print info if already initialized) and exit.
-    let _ = initialize_or_return(
-        &owner,
-        &member,
-        &init_marker,
-        &team_id_path,
-        &member_id_path,
-        already_initialized
-    ).await?;
+    let files = StateFiles::new(&owner_dir_pb);
+    let _ = initialize_or_return(&owner, &member, &files, &cfg.target, sync_interval).await?;
+
+    // Stop the daemons cleanly so the working dirs can be shipped as-is.
+    member.shutdown(DEFAULT_SHUTDOWN_GRACE).await?;
+    owner.shutdown(DEFAULT_SHUTDOWN_GRACE).await?;
     Ok(())
 }
-
-
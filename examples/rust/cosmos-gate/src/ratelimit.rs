@@ -0,0 +1,165 @@
+//! Per-caller token-bucket rate limiting for `/authorize`.
+//!
+//! Each `keycloak_id` gets a bucket holding up to `burst` tokens that refills
+//! at `rate` tokens per second; a request spends one token. Buckets that have
+//! been idle long enough to refill completely are evicted periodically, since
+//! they are indistinguishable from a fresh bucket.
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+
+/// How often idle buckets are evicted.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Lowest accepted `rate_limit`: one request per day.
+pub const MIN_RATE: f64 = 1.0 / 86_400.0;
+
+/// Default for the `rate_burst` setting.
+pub const DEFAULT_RATE_BURST: u32 = 10;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug)]
+struct Buckets {
+    map: HashMap<String, Bucket>,
+    last_sweep: Instant,
+}
+
+/// A token-bucket rate limiter keyed by caller.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    /// Allows `rate` requests per second per key, in bursts of up to `burst`.
+    pub fn new(rate: f64, burst: u32) -> Result<Self> {
+        if !(rate.is_finite() && rate >= MIN_RATE) {
+            bail!("rate limit must be at least {MIN_RATE} requests per second, got {rate}");
+        }
+        if burst == 0 {
+            bail!("rate limit burst must be at least 1");
+        }
+        Ok(Self {
+            rate,
+            burst: f64::from(burst),
+            buckets: Mutex::new(Buckets {
+                map: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+        })
+    }
+
+    /// Spends a token for `key`, or returns how long until one is available.
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        if now.saturating_duration_since(buckets.last_sweep) >= SWEEP_INTERVAL {
+            self.sweep(&mut buckets, now);
+        }
+
+        let bucket = buckets.map.entry(key.to_owned()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(secs((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    fn sweep(&self, buckets: &mut Buckets, now: Instant) {
+        let refill = secs(self.burst / self.rate);
+        buckets
+            .map
+            .retain(|_, b| now.saturating_duration_since(b.updated) < refill);
+        buckets.last_sweep = now;
+    }
+
+    /// Number of keys currently tracked.
+    pub fn tracked_keys(&self) -> usize {
+        self.buckets
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .map
+            .len()
+    }
+}
+
+/// Converts seconds to a [`Duration`], saturating instead of panicking.
+fn secs(secs: f64) -> Duration {
+    Duration::try_from_secs_f64(secs).unwrap_or(Duration::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit_burst() {
+        const N: u32 = 5;
+        let limiter = RateLimiter::new(1.0, N).unwrap();
+        let now = Instant::now();
+        for _ in 0..N {
+            limiter.check_at("alice", now).unwrap();
+        }
+        let retry = limiter.check_at("alice", now).unwrap_err();
+        assert!(retry > Duration::ZERO && retry <= Duration::from_secs(1));
+
+        // Other callers have their own bucket.
+        limiter.check_at("bob", now).unwrap();
+
+        // One token refills after a second.
+        let later = now + Duration::from_secs(1);
+        limiter.check_at("alice", later).unwrap();
+        assert!(limiter.check_at("alice", later).is_err());
+    }
+
+    #[test]
+    fn test_rate_limit_evicts_idle_keys() {
+        let limiter = RateLimiter::new(10.0, 10).unwrap();
+        let now = Instant::now();
+        limiter.check_at("alice", now).unwrap();
+        limiter.check_at("bob", now).unwrap();
+        assert_eq!(limiter.tracked_keys(), 2);
+
+        limiter.check_at("carol", now + SWEEP_INTERVAL).unwrap();
+        assert_eq!(limiter.tracked_keys(), 1);
+    }
+
+    #[test]
+    fn test_rate_limit_slowest_rate() {
+        let limiter = RateLimiter::new(MIN_RATE, u32::MAX).unwrap();
+        let now = Instant::now();
+        limiter.check_at("alice", now).unwrap();
+        limiter.check_at("bob", now + SWEEP_INTERVAL).unwrap();
+        assert_eq!(limiter.tracked_keys(), 2);
+    }
+
+    #[test]
+    fn test_rate_limit_rejects_bad_config() {
+        assert!(RateLimiter::new(0.0, 1).is_err());
+        assert!(RateLimiter::new(f64::NAN, 1).is_err());
+        assert!(RateLimiter::new(1e-19, 1).is_err());
+        assert!(RateLimiter::new(1.0, 0).is_err());
+    }
+}
@@ -0,0 +1,124 @@
+//! A [`Client`] handle that reconnects when its daemon connection breaks.
+//!
+//! A [`Client`] whose daemon connection is broken fails every call with an
+//! IPC error. [`ReconnectingClient::with_retry`] notices this, reconnects
+//! with [`connect_client`] as [`crate::ClientCtx`] does at startup, and
+//! retries the operation once on the new connection. [`client_alive`] is a
+//! cheap liveness probe for a daemon connection.
+//!
+//! Nothing here restarts the daemon. A daemon started again by someone else
+//! would also bind a new AQC port, which needs
+//! [`crate::refresh_owner_net_identifier`]; the server handles that by being
+//! restarted along with its daemon.
+
+use std::{future::Future, net::Ipv4Addr, path::PathBuf, sync::Arc, time::Duration};
+
+use anyhow::{Context as _, Result};
+use aranya_client::client::{Client, DeviceId};
+pub use aranya_example_common::{client_alive, ping, DEFAULT_PING_TIMEOUT};
+use aranya_example_common::{connect_client, uds_path, ClientCtx};
+use aranya_util::Addr;
+use tokio::{sync::RwLock, time::timeout};
+use tracing::{info, warn};
+
+/// Reports whether `err` means the daemon connection itself is broken, as
+/// opposed to the daemon rejecting the operation.
+pub fn is_connection_error(err: &aranya_client::Error) -> bool {
+    matches!(err, aranya_client::Error::Ipc(_))
+}
+
+/// A shared [`Client`] that is replaced when its daemon connection breaks.
+#[derive(Debug)]
+pub struct ReconnectingClient {
+    uds_path: PathBuf,
+    aqc_server_addr: Addr,
+    client: RwLock<Arc<Client>>,
+}
+
+impl ReconnectingClient {
+    /// Wraps an already connected `client` that was built with `uds_path`
+    /// and `aqc_server_addr`.
+    pub fn new(client: Arc<Client>, uds_path: impl Into<PathBuf>, aqc_server_addr: Addr) -> Self {
+        Self {
+            uds_path: uds_path.into(),
+            aqc_server_addr,
+            client: RwLock::new(client),
+        }
+    }
+
+    /// Shares `ctx`'s client, reconnecting to the daemon in its work dir.
+    pub fn for_ctx(ctx: &ClientCtx) -> Self {
+        Self::new(
+            Arc::clone(&ctx.client),
+            uds_path(ctx.work_dir()),
+            Addr::from((Ipv4Addr::LOCALHOST, 0)),
+        )
+    }
+
+    /// Connects to the daemon listening on `uds_path`.
+    pub async fn connect(uds_path: impl Into<PathBuf>, aqc_server_addr: Addr) -> Result<Self> {
+        let uds_path = uds_path.into();
+        let client = connect_client(&uds_path, &aqc_server_addr)
+            .await
+            .context("unable to initialize client")?;
+        Ok(Self::new(Arc::new(client), uds_path, aqc_server_addr))
+    }
+
+    /// Returns the current client.
+    ///
+    /// Calls made directly on it are not retried.
+    pub async fn client(&self) -> Arc<Client> {
+        Arc::clone(&*self.client.read().await)
+    }
+
+    /// Runs `op`, reconnecting and running it once more if it fails with a
+    /// connection error.
+    pub async fn with_retry<T, F, Fut>(&self, op: F) -> Result<T>
+    where
+        F: Fn(Arc<Client>) -> Fut,
+        Fut: Future<Output = aranya_client::Result<T>>,
+    {
+        let client = self.client().await;
+        match op(Arc::clone(&client)).await {
+            Err(err) if is_connection_error(&err) => {
+                warn!("lost connection to daemon, reconnecting: {err}");
+                let client = self.reconnect(&client).await?;
+                Ok(op(client).await?)
+            }
+            res => Ok(res?),
+        }
+    }
+
+    /// Like [`ping`], but through [`Self::with_retry`], so a broken
+    /// connection is replaced before the probe fails. `limit` bounds the
+    /// whole attempt, reconnect included.
+    pub async fn ping(&self, limit: Duration) -> Result<DeviceId> {
+        timeout(
+            limit,
+            self.with_retry(|client| async move { client.get_device_id().await }),
+        )
+        .await
+        .with_context(|| format!("daemon did not respond within {limit:?}"))?
+        .context("daemon is unavailable")
+    }
+
+    /// Replaces `stale` with a new connection, unless another caller already
+    /// has.
+    async fn reconnect(&self, stale: &Arc<Client>) -> Result<Arc<Client>> {
+        let mut current = self.client.write().await;
+        if !Arc::ptr_eq(&current, stale) {
+            return Ok(Arc::clone(&current));
+        }
+        let client = connect_client(&self.uds_path, &self.aqc_server_addr)
+            .await
+            .with_context(|| {
+                format!(
+                    "unable to reconnect to daemon at {}",
+                    self.uds_path.display()
+                )
+            })?;
+        info!(uds_path = %self.uds_path.display(), "reconnected to daemon");
+        *current = Arc::new(client);
+        Ok(Arc::clone(&current))
+    }
+}
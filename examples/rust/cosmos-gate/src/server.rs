@@ -1,17 +1,50 @@
-use std::{env, net::SocketAddr, path::PathBuf};
-use anyhow::{Context as _, Result, bail};
-use tracing_subscriber::{layer::SubscriberExt, prelude::*, util::SubscriberInitExt, EnvFilter};
-use tracing::info;
-use axum::Router;
+use std::{collections::HashMap, env, net::SocketAddr, path::PathBuf, sync::Arc};
 
+use anyhow::{bail, Context as _, Result};
+use axum::Router;
 use cosmos_gate::{
-    AppState, ClientCtx, DaemonPath, build_router, init_marker_path, read_team_id, team_id_path,
-    member_id_path, read_member_id,
+    auth::KeycloakAuth,
+    build_router,
+    config::{PartialConfig, ResolvedConfig, DEFAULT_LOG_LEVEL},
+    envelope::{load_or_create_signing_key, verifying_key_path},
+    ratelimit::RateLimiter,
+    read_member_id, read_members, read_targets, read_team_id,
+    reconnect::ReconnectingClient,
+    refresh_owner_net_identifier, verify_target_on_team, verify_targets_in_roster, AppState,
+    ClientCtx, MemberRecord, StateFiles, DEFAULT_SHUTDOWN_GRACE,
 };
+use tracing::{info, warn};
+use tracing_subscriber::{layer::SubscriberExt, prelude::*, util::SubscriberInitExt, EnvFilter};
 
-/// Args: <daemon_path> <owner_work_dir> [rest_bind_addr]
+/// Args: [--skip-checks] [--signed-envelope] [daemon_path] [owner_work_dir] [rest_bind_addr]
+///
+/// Missing args fall back to the environment and config file; see
+/// [`cosmos_gate::config`].
 #[tokio::main]
 async fn main() -> Result<()> {
+    let (flags, args): (Vec<_>, Vec<_>) =
+        env::args().skip(1).partition(|arg| arg.starts_with("--"));
+    let mut skip_checks = false;
+    let mut signed_envelope = false;
+    for flag in &flags {
+        match flag.as_str() {
+            "--skip-checks" => skip_checks = true,
+            "--signed-envelope" => signed_envelope = true,
+            _ => bail!("unknown flag: {flag}"),
+        }
+    }
+    let mut args = args.into_iter();
+    let cfg = ResolvedConfig::load(PartialConfig {
+        daemon_path: args.next().map(PathBuf::from),
+        owner_dir: args.next().map(PathBuf::from),
+        bind_addr: args
+            .next()
+            .map(|s| s.parse::<SocketAddr>())
+            .transpose()
+            .context("invalid [rest_bind_addr]")?,
+        ..Default::default()
+    })?;
+
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::fmt::layer()
@@ -19,48 +52,138 @@ async fn main() -> Result<()> {
                 .with_target(false)
                 .compact()
                 .with_filter(
-                    EnvFilter::try_from_env("COSMOS_GATE_LOG")
-                        .unwrap_or_else(|_| EnvFilter::new("info")),
+                    EnvFilter::try_new(&cfg.log_level)
+                        .unwrap_or_else(|_| EnvFilter::new(DEFAULT_LOG_LEVEL)),
                 ),
         )
         .init();
 
-    let mut args = env::args();
-    let _exe = args.next();
-    let daemon_exe = args.next().context("missing <daemon_path>")?;
-    let owner_dir = args.next().context("missing <owner_work_dir>")?;
-    let bind = args
-        .next()
-        .unwrap_or_else(|| "127.0.0.1:8080".to_string())
-        .parse::<SocketAddr>()
-        .context("invalid [rest_bind_addr]")?;
-
-    let daemon_path = DaemonPath(daemon_exe.into());
-    let owner_dir_pb = PathBuf::from(&owner_dir);
+    let daemon_path = cfg.daemon_path()?;
+    let owner_dir_pb = cfg.owner_dir()?;
+    let bind = cfg.bind_addr;
 
     // Require prior initialization.
-    let init_marker = init_marker_path(&owner_dir_pb);
-    let team_id_file = team_id_path(&owner_dir_pb);
-    let member_id_file = member_id_path(&owner_dir_pb);
-    if !tokio::fs::metadata(&init_marker).await.is_ok() {
+    let files = StateFiles::new(&owner_dir_pb);
+    if !files.is_initialized().await {
         bail!("not initialized; run the init binary first to onboard");
     }
-    let owner_team_id = read_team_id(&team_id_file).await?;
-    let target_member_id = read_member_id(&member_id_file).await?;
+    let owner_team_id = read_team_id(&files.team_id).await?;
+    let members = if tokio::fs::metadata(&files.members).await.is_ok() {
+        read_members(&files.members).await?
+    } else {
+        // Gate dirs initialized before the roster existed only record the
+        // single member's id.
+        let device_id = read_member_id(&files.member_id).await?;
+        vec![MemberRecord {
+            name: "member".into(),
+            device_id,
+            aqc_net_id: None,
+        }]
+    };
+    if members.is_empty() {
+        bail!(
+            "no members in {}; run the init binary first to onboard",
+            files.members.display()
+        );
+    }
+    info!(
+        "onboarded members: {:?}",
+        members.iter().map(|m| &m.name).collect::<Vec<_>>()
+    );
+    let targets = if tokio::fs::metadata(&files.targets).await.is_ok() {
+        read_targets(&files.targets).await?
+    } else if let [member] = members.as_slice() {
+        // No explicit routing: send the configured target to the only
+        // member.
+        HashMap::from([(cosmos_gate::normalize_target(&cfg.target), member.device_id)])
+    } else {
+        bail!(
+            "{} is missing; re-run the init binary",
+            files.targets.display()
+        );
+    };
+    verify_targets_in_roster(&targets, &members)?;
+    info!("routing targets: {:?}", targets.keys().collect::<Vec<_>>());
 
     // Spawn owner daemon/client only (member no longer needed here).
-    let owner = ClientCtx::new("owner", &daemon_path, owner_dir_pb.clone()).await?;
+    let owner =
+        ClientCtx::new("owner", &daemon_path, owner_dir_pb.clone(), cfg.daemon_logs).await?;
+    refresh_owner_net_identifier(&owner, owner_team_id, &files.owner_net_id).await?;
+
+    if skip_checks {
+        info!("skipping startup checks");
+    } else {
+        // Every target routes to one of the members, so this covers the
+        // targets too.
+        for member in &members {
+            verify_target_on_team(&owner.client, owner_team_id, member.device_id).await?;
+        }
+    }
+
+    let envelope_key = if signed_envelope {
+        let key = load_or_create_signing_key(&owner.client, &owner_dir_pb).await?;
+        info!(
+            "signing command envelopes; verifying key at {}",
+            verifying_key_path(&owner_dir_pb).display()
+        );
+        Some(Arc::new(key))
+    } else {
+        None
+    };
+
+    let auth = match (cfg.jwks_url.clone(), cfg.audience.clone()) {
+        (Some(jwks_url), Some(audience)) => {
+            info!("validating bearer tokens against {jwks_url}");
+            let mut auth = KeycloakAuth::new(jwks_url, audience);
+            if let Some(issuer) = cfg.issuer.clone() {
+                auth = auth.issuer(issuer);
+            }
+            Some(Arc::new(auth))
+        }
+        (None, None) => {
+            warn!("no Keycloak JWKS configured; /authorize is unauthenticated");
+            None
+        }
+        _ => bail!("jwks_url and audience must be configured together"),
+    };
+
+    let rate_limit = match cfg.rate_limit {
+        Some(rate) => {
+            info!(
+                "rate limiting to {rate}/s per keycloak_id, burst {}",
+                cfg.rate_burst
+            );
+            Some(Arc::new(RateLimiter::new(rate, cfg.rate_burst)?))
+        }
+        None => None,
+    };
 
     // Build REST state and router.
     let state = AppState {
-        owner: owner.client.clone(),
+        owner: Arc::new(ReconnectingClient::for_ctx(&owner)),
         owner_team_id,
-        target_member_id,
+        targets: Arc::new(targets),
+        request_timeout: cfg.request_timeout,
+        ready_timeout: cfg.ready_timeout,
+        envelope_key,
+        auth,
+        rate_limit,
     };
-    let app: Router = build_router(state);
+    let base_path = cfg.base_path.as_deref();
+    let app: Router = build_router(state, base_path);
 
-    info!("REST listening on http://{}", bind);
+    info!(
+        "REST listening on http://{}{}",
+        bind,
+        base_path.unwrap_or_default()
+    );
     let listener = tokio::net::TcpListener::bind(bind).await?;
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async {
+            let _ = tokio::signal::ctrl_c().await;
+            info!("shutting down");
+        })
+        .await?;
+    owner.shutdown(DEFAULT_SHUTDOWN_GRACE).await?;
     Ok(())
-}
\ No newline at end of file
+}
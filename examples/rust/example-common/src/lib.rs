@@ -0,0 +1,703 @@
+//! Daemon spawning and client setup shared by the Aranya Rust examples.
+//!
+//! [`ClientCtx`] writes a daemon config, starts `aranya-daemon` in a working
+//! directory, and connects a [`Client`] to it once its UDS is up. The
+//! pieces it is built from ([`Daemon`], [`wait_for_uds`], [`connect_client`])
+//! are public for callers that need something different.
+
+use std::{
+    env,
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    process::{self, Stdio},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{bail, Context as _, Result};
+use aranya_client::client::{Client, DeviceId, KeyBundle};
+use aranya_util::Addr;
+use backon::{ExponentialBuilder, Retryable};
+use rustix::{
+    process::{kill_process, Pid, Signal},
+    shm,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs,
+    io::{AsyncBufReadExt as _, AsyncRead, BufReader},
+    net::UnixStream,
+    process::{Child, Command},
+    time::{sleep, timeout, Instant},
+};
+use tracing::{debug, info, warn};
+
+#[derive(Clone, Debug)]
+pub struct DaemonPath(pub PathBuf);
+
+/// Where a spawned daemon's stdout and stderr go.
+///
+/// Parses from (and deserializes as) `"files"`, `"tracing"`, or `"inherit"`.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DaemonLogs {
+    /// Append to `daemon.out` and `daemon.err` in the daemon's logs dir.
+    #[default]
+    Files,
+    /// Forward each line to `tracing`, tagged with the daemon's name.
+    Tracing,
+    /// Inherit this process's stdout and stderr.
+    Inherit,
+}
+
+impl FromStr for DaemonLogs {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "files" => Ok(Self::Files),
+            "tracing" => Ok(Self::Tracing),
+            "inherit" => Ok(Self::Inherit),
+            _ => bail!("invalid daemon log mode `{s}`: expected `files`, `tracing`, or `inherit`"),
+        }
+    }
+}
+
+#[derive(Debug)]
+#[clippy::has_significant_drop]
+pub struct Daemon {
+    // NB: This has important drop side effects.
+    proc: Child,
+    work_dir: PathBuf,
+}
+
+/// The daemon's `config.toml`.
+#[derive(Clone, Debug, Serialize)]
+pub struct DaemonConfig {
+    pub name: String,
+    pub runtime_dir: PathBuf,
+    pub state_dir: PathBuf,
+    pub cache_dir: PathBuf,
+    pub logs_dir: PathBuf,
+    pub config_dir: PathBuf,
+    pub aqc: AqcConfig,
+    pub afc: AfcConfig,
+    pub sync: SyncConfig,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AqcConfig {
+    pub enable: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AfcConfig {
+    pub enable: bool,
+    pub shm_path: String,
+    pub max_chans: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SyncConfig {
+    pub quic: QuicSyncConfig,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct QuicSyncConfig {
+    pub enable: bool,
+    pub addr: Addr,
+}
+
+impl DaemonConfig {
+    /// The example's defaults for a daemon named `user_name` in `work_dir`.
+    ///
+    /// The SHM path is `/shm_<user_name>_<pid>`, so daemons spawned by
+    /// different processes never share (or unlink) each other's segment.
+    pub fn new(user_name: &str, work_dir: &Path) -> Self {
+        Self {
+            name: user_name.to_string(),
+            runtime_dir: work_dir.join("run"),
+            state_dir: work_dir.join("state"),
+            cache_dir: work_dir.join("cache"),
+            logs_dir: work_dir.join("logs"),
+            config_dir: work_dir.join("config"),
+            aqc: AqcConfig { enable: true },
+            afc: AfcConfig {
+                enable: true,
+                shm_path: format!("/shm_{user_name}_{}", process::id()),
+                max_chans: 100,
+            },
+            sync: SyncConfig {
+                quic: QuicSyncConfig {
+                    enable: true,
+                    addr: Addr::from((Ipv4Addr::LOCALHOST, 0)),
+                },
+            },
+        }
+    }
+
+    /// Serializes the config as TOML.
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string(self).context("unable to serialize daemon config")
+    }
+}
+
+impl Daemon {
+    /// Spawns a daemon in `work_dir` with the default config.
+    ///
+    /// `work_dir` is used as-is; callers that accept `${VAR}` references
+    /// resolve them first with [`expand_env_vars`].
+    pub async fn spawn(path: &DaemonPath, user_name: &str, work_dir: &Path) -> Result<Self> {
+        let cfg = DaemonConfig::new(user_name, work_dir);
+        Self::spawn_with_config(path, work_dir, &cfg, DaemonLogs::default()).await
+    }
+
+    /// Spawns a daemon in `work_dir` with a caller-supplied config.
+    ///
+    /// Like [`Daemon::spawn`], `work_dir` and the directories in `cfg` must
+    /// already be resolved.
+    pub async fn spawn_with_config(
+        path: &DaemonPath,
+        work_dir: &Path,
+        cfg: &DaemonConfig,
+        logs: DaemonLogs,
+    ) -> Result<Self> {
+        fs::create_dir_all(work_dir).await?;
+
+        // Ensure no stale POSIX SHM exists from previous runs (matches aranya example).
+        let shm = &cfg.afc.shm_path;
+        if shm::unlink(shm).is_ok() {
+            warn!(%shm, "removed stale SHM segment");
+        }
+
+        for dir in [
+            &cfg.runtime_dir,
+            &cfg.state_dir,
+            &cfg.cache_dir,
+            &cfg.logs_dir,
+            &cfg.config_dir,
+        ] {
+            fs::create_dir_all(dir)
+                .await
+                .with_context(|| format!("unable to create directory: {}", dir.display()))?;
+        }
+
+        let cfg_path = work_dir.join("config.toml");
+        fs::write(&cfg_path, cfg.to_toml()?).await?;
+
+        // Spawn daemon.
+        let cfg_path = cfg_path.as_os_str().to_str().context("cfg_path UTF-8")?;
+        let mut cmd = Command::new(&path.0);
+        cmd.kill_on_drop(true)
+            .current_dir(work_dir)
+            .args(["--config", cfg_path]);
+        match logs {
+            DaemonLogs::Files => {
+                cmd.stdout(open_log(&cfg.logs_dir.join("daemon.out"))?)
+                    .stderr(open_log(&cfg.logs_dir.join("daemon.err"))?);
+            }
+            DaemonLogs::Tracing => {
+                cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+            }
+            DaemonLogs::Inherit => {}
+        }
+        debug!(?cmd, "spawning daemon");
+        let mut proc = cmd.spawn().context("unable to spawn daemon")?;
+        if logs == DaemonLogs::Tracing {
+            if let Some(stdout) = proc.stdout.take() {
+                tokio::spawn(forward_lines(stdout, cfg.name.clone(), "stdout"));
+            }
+            if let Some(stderr) = proc.stderr.take() {
+                tokio::spawn(forward_lines(stderr, cfg.name.clone(), "stderr"));
+            }
+        }
+        Ok(Daemon {
+            proc,
+            work_dir: work_dir.into(),
+        })
+    }
+
+    /// The daemon's working directory.
+    pub fn work_dir(&self) -> &Path {
+        &self.work_dir
+    }
+
+    /// Reports whether the daemon process is still running.
+    pub fn is_alive(&mut self) -> Result<bool> {
+        Ok(self
+            .proc
+            .try_wait()
+            .context("unable to query daemon status")?
+            .is_none())
+    }
+
+    /// Returns an error carrying the exit status if the daemon has exited.
+    pub fn ensure_running(&mut self) -> Result<()> {
+        match self
+            .proc
+            .try_wait()
+            .context("unable to query daemon status")?
+        {
+            None => Ok(()),
+            Some(status) => match status.code() {
+                Some(code) => bail!("daemon exited with code {code}"),
+                None => bail!("daemon exited: {status}"),
+            },
+        }
+    }
+
+    /// Asks the daemon to exit with SIGTERM, killing it if it has not exited
+    /// within `grace`.
+    ///
+    /// Returns an error if the daemon had already exited.
+    pub async fn shutdown(mut self, grace: Duration) -> Result<()> {
+        self.ensure_running()?;
+        let pid = self
+            .proc
+            .id()
+            .and_then(|id| i32::try_from(id).ok())
+            .and_then(Pid::from_raw)
+            .context("daemon has no pid")?;
+        kill_process(pid, Signal::Term).context("unable to send SIGTERM to daemon")?;
+        match timeout(grace, self.proc.wait()).await {
+            Ok(status) => {
+                let status = status.context("unable to wait for daemon")?;
+                debug!(%status, "daemon exited");
+            }
+            Err(_) => {
+                warn!(?grace, "daemon did not exit after SIGTERM; killing");
+                self.proc.kill().await.context("unable to kill daemon")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Daemon {
+    /// Kills the daemon and waits up to [`DAEMON_REAP_TIMEOUT`] for it to
+    /// exit, so that its work dir can be removed right after.
+    fn drop(&mut self) {
+        if !matches!(self.proc.try_wait(), Ok(None)) {
+            return;
+        }
+        if let Err(err) = self.proc.start_kill() {
+            warn!(%err, "unable to kill daemon");
+            return;
+        }
+        let deadline = std::time::Instant::now() + DAEMON_REAP_TIMEOUT;
+        while std::time::Instant::now() < deadline {
+            match self.proc.try_wait() {
+                Ok(None) => std::thread::sleep(Duration::from_millis(10)),
+                Ok(Some(status)) => {
+                    debug!(%status, "daemon exited");
+                    return;
+                }
+                Err(err) => {
+                    warn!(%err, "unable to wait for daemon");
+                    return;
+                }
+            }
+        }
+        warn!(timeout = ?DAEMON_REAP_TIMEOUT, "daemon did not exit after SIGKILL");
+    }
+}
+
+/// How long dropping a [`Daemon`] waits for the killed process to exit.
+pub const DAEMON_REAP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Opens a daemon log file for appending.
+fn open_log(path: &Path) -> Result<Stdio> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("unable to open daemon log: {}", path.display()))?;
+    Ok(file.into())
+}
+
+/// Forwards each line read from `r` to `tracing`.
+async fn forward_lines<R>(r: R, daemon: String, stream: &'static str)
+where
+    R: AsyncRead + Unpin,
+{
+    let mut lines = BufReader::new(r).lines();
+    loop {
+        match lines.next_line().await {
+            Ok(Some(line)) => info!(%daemon, stream, "{line}"),
+            Ok(None) => break,
+            Err(err) => {
+                warn!(%daemon, stream, %err, "unable to read daemon output");
+                break;
+            }
+        }
+    }
+}
+
+/// How long to wait for a daemon to bind its UDS; see [`wait_for_uds`].
+pub const DAEMON_START_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Polls until the UDS at `path` exists and accepts connections.
+pub async fn wait_for_uds(path: &Path, timeout: Duration) -> Result<()> {
+    let start = Instant::now();
+    loop {
+        match UnixStream::connect(path).await {
+            Ok(_) => {
+                debug!(elapsed = ?start.elapsed(), path = %path.display(), "UDS is ready");
+                return Ok(());
+            }
+            Err(err) if start.elapsed() >= timeout => {
+                return Err(err).with_context(|| {
+                    format!("daemon did not bind UDS within {}s", timeout.as_secs_f32())
+                });
+            }
+            Err(_) => sleep(Duration::from_millis(10)).await,
+        }
+    }
+}
+
+/// Default grace period for [`Daemon::shutdown`].
+pub const DEFAULT_SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// The UDS path a daemon with working dir `work_dir` listens on.
+pub fn uds_path(work_dir: &Path) -> PathBuf {
+    work_dir.join("run").join("uds.sock")
+}
+
+/// Connects to the daemon listening on `uds_path`, retrying with
+/// exponential backoff.
+pub async fn connect_client(
+    uds_path: &Path,
+    aqc_server_addr: &Addr,
+) -> aranya_client::Result<Client> {
+    (|| {
+        Client::builder()
+            .daemon_uds_path(uds_path)
+            .aqc_server_addr(aqc_server_addr)
+            .connect()
+    })
+    .retry(ExponentialBuilder::default())
+    .await
+}
+
+/// Default timeout for [`ping`] and [`client_alive`].
+pub const DEFAULT_PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Makes the cheapest daemon round trip available, failing if it errors or
+/// takes longer than `limit`.
+pub async fn ping(client: &Client, limit: Duration) -> Result<DeviceId> {
+    timeout(limit, client.get_device_id())
+        .await
+        .with_context(|| format!("daemon did not respond within {limit:?}"))?
+        .context("daemon is unavailable")
+}
+
+/// Reports whether `client`'s daemon answers within `limit`.
+pub async fn client_alive(client: &Client, limit: Duration) -> bool {
+    ping(client, limit).await.is_ok()
+}
+
+/// An Aranya device: a spawned daemon and a client connected to it.
+pub struct ClientCtx {
+    /// The daemon's name.
+    pub name: String,
+    pub client: Arc<Client>,
+    pub pk: KeyBundle,
+    pub id: DeviceId,
+    // NB: `daemon` must be dropped (and reaped) before `work_dir` is removed.
+    daemon: Daemon,
+    work_dir: WorkDir,
+}
+
+impl ClientCtx {
+    /// Spawns a daemon in `work_dir` and connects to it.
+    ///
+    /// `work_dir` is used as-is; callers that accept `${VAR}` references
+    /// resolve them first with [`expand_env_vars`].
+    pub async fn new(
+        user_name: &str,
+        daemon_path: &DaemonPath,
+        work_dir: PathBuf,
+        logs: DaemonLogs,
+    ) -> Result<Self> {
+        let created = fs::metadata(&work_dir).await.is_err();
+        Self::spawn(
+            user_name,
+            daemon_path,
+            WorkDir::new(work_dir, created),
+            logs,
+        )
+        .await
+    }
+
+    /// Creates a `ClientCtx` in a fresh temporary directory that is removed
+    /// on drop.
+    ///
+    /// The directory is `$TMPDIR/aranya-<instance>` and the daemon's SHM
+    /// path is `/shm_<instance>_<pid>`, where `<instance>` comes from
+    /// [`instance_name`], so concurrent instances never share either. A stale
+    /// directory or SHM segment left under the same name is removed first.
+    pub async fn with_temp_dir(
+        user_name: &str,
+        daemon_path: &DaemonPath,
+        logs: DaemonLogs,
+    ) -> Result<Self> {
+        let name = instance_name(user_name);
+        let work_dir = env::temp_dir().join(format!("aranya-{name}"));
+        if fs::metadata(&work_dir).await.is_ok() {
+            warn!(work_dir = %work_dir.display(), "removing stale work dir");
+            fs::remove_dir_all(&work_dir).await.with_context(|| {
+                format!("unable to remove stale work dir: {}", work_dir.display())
+            })?;
+        }
+        fs::create_dir_all(&work_dir)
+            .await
+            .with_context(|| format!("unable to create work dir: {}", work_dir.display()))?;
+        let mut work_dir = WorkDir::new(work_dir, true);
+        work_dir.cleanup_on_drop = true;
+        Self::spawn(&name, daemon_path, work_dir, logs).await
+    }
+
+    async fn spawn(
+        user_name: &str,
+        daemon_path: &DaemonPath,
+        work_dir: WorkDir,
+        logs: DaemonLogs,
+    ) -> Result<Self> {
+        info!(user_name, "creating `ClientCtx`");
+
+        // Spawn daemon in given work_dir.
+        let cfg = DaemonConfig::new(user_name, &work_dir.path);
+        let mut daemon = Daemon::spawn_with_config(daemon_path, &work_dir.path, &cfg, logs).await?;
+
+        let uds_sock = uds_path(&work_dir.path);
+
+        // Wait for the daemon to start and bind its UDS.
+        if let Err(err) = wait_for_uds(&uds_sock, DAEMON_START_TIMEOUT).await {
+            // A dead daemon is a more useful error than the timeout.
+            daemon.ensure_running()?;
+            return Err(err);
+        }
+
+        // Connect client.
+        let any_addr = Addr::from((Ipv4Addr::LOCALHOST, 0));
+        let client = match connect_client(&uds_sock, &any_addr).await {
+            Ok(client) => client,
+            Err(err) => {
+                // A dead daemon is a more useful error than the failed connect.
+                daemon.ensure_running()?;
+                return Err(err).context("unable to initialize client");
+            }
+        };
+
+        // Fetch client identity info.
+        let pk = client
+            .get_key_bundle()
+            .await
+            .context("expected key bundle")?;
+        let id = client.get_device_id().await.context("expected device id")?;
+
+        Ok(Self {
+            name: user_name.to_owned(),
+            client: Arc::new(client),
+            pk,
+            id,
+            daemon,
+            work_dir,
+        })
+    }
+
+    /// Removes the work dir on drop, after the daemon has been killed.
+    ///
+    /// Only takes effect if this `ClientCtx` created the directory.
+    pub fn cleanup_on_drop(mut self, enabled: bool) -> Self {
+        if enabled && !self.work_dir.created {
+            warn!(
+                work_dir = %self.work_dir.path.display(),
+                "not cleaning up pre-existing work dir"
+            );
+        }
+        self.work_dir.cleanup_on_drop = enabled;
+        self
+    }
+
+    pub fn work_dir(&self) -> &Path {
+        &self.work_dir.path
+    }
+
+    /// Returns an error carrying the exit status if the daemon has exited.
+    pub fn ensure_daemon_running(&mut self) -> Result<()> {
+        self.daemon.ensure_running()
+    }
+
+    /// Gracefully shuts down the daemon; see [`Daemon::shutdown`].
+    pub async fn shutdown(self, grace: Duration) -> Result<()> {
+        let Self {
+            client,
+            daemon,
+            work_dir,
+            ..
+        } = self;
+        drop(client);
+        daemon.shutdown(grace).await?;
+        // Clean up (if enabled) only after the daemon has exited.
+        drop(work_dir);
+        Ok(())
+    }
+
+    /// Reports whether the daemon answers within `limit`; see
+    /// [`client_alive`].
+    pub async fn ping(&self, limit: Duration) -> bool {
+        client_alive(&self.client, limit).await
+    }
+
+    pub async fn aranya_local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.client.local_addr().await?)
+    }
+
+    /// Returns the address the client's AQC server is bound to, with the
+    /// actual port if an ephemeral one was requested.
+    pub fn aqc_local_addr(&self) -> Result<SocketAddr> {
+        let aqc = self
+            .client
+            .aqc()
+            .context("AQC is not enabled for this client")?;
+        Ok(aqc.server_addr())
+    }
+}
+
+/// Returns `<user_name>-<pid>-<n>`, where `n` counts calls within this
+/// process, for naming per-instance temp dirs and SHM paths.
+pub fn instance_name(user_name: &str) -> String {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    format!("{user_name}-{}-{n}", process::id())
+}
+
+/// A `ClientCtx` working directory.
+#[derive(Debug)]
+struct WorkDir {
+    path: PathBuf,
+    /// Whether the `ClientCtx` created the directory.
+    created: bool,
+    cleanup_on_drop: bool,
+}
+
+impl WorkDir {
+    fn new(path: PathBuf, created: bool) -> Self {
+        Self {
+            path,
+            created,
+            cleanup_on_drop: false,
+        }
+    }
+}
+
+impl Drop for WorkDir {
+    fn drop(&mut self) {
+        if !(self.cleanup_on_drop && self.created) {
+            return;
+        }
+        debug!(work_dir = %self.path.display(), "removing work dir");
+        if let Err(err) = std::fs::remove_dir_all(&self.path) {
+            warn!(work_dir = %self.path.display(), %err, "unable to remove work dir");
+        }
+    }
+}
+
+/// Expands `${VAR}` references in `path` from the process environment.
+///
+/// Paths without references (or that aren't UTF-8) are returned unchanged.
+pub fn expand_env_vars(path: &Path) -> Result<PathBuf> {
+    let Some(s) = path.to_str() else {
+        return Ok(path.into());
+    };
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find('}')
+            .with_context(|| format!("unterminated `${{` in path: {s}"))?;
+        let name = &after[..end];
+        let value = env::var(name)
+            .with_context(|| format!("undefined environment variable `{name}` in path: {s}"))?;
+        out.push_str(&value);
+        rest = &after[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_daemon_config_toml() {
+        let cfg = DaemonConfig::new("owner", Path::new("/tmp/owner"));
+        let value: toml::Table = toml::from_str(&cfg.to_toml().unwrap()).unwrap();
+        assert_eq!(value["name"].as_str(), Some("owner"));
+        assert_eq!(value["runtime_dir"].as_str(), Some("/tmp/owner/run"));
+        assert_eq!(value["aqc"]["enable"].as_bool(), Some(true));
+        assert_eq!(
+            value["afc"]["shm_path"].as_str(),
+            Some(format!("/shm_owner_{}", process::id()).as_str())
+        );
+        assert_eq!(value["afc"]["max_chans"].as_integer(), Some(100));
+        assert_eq!(value["sync"]["quic"]["addr"].as_str(), Some("127.0.0.1:0"));
+    }
+
+    #[test]
+    fn test_daemon_drop_reaps() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let _guard = rt.enter();
+        let proc = Command::new("sleep").arg("30").spawn().unwrap();
+        let pid = Pid::from_raw(i32::try_from(proc.id().unwrap()).unwrap()).unwrap();
+        drop(Daemon {
+            proc,
+            work_dir: PathBuf::new(),
+        });
+        // A reaped child no longer exists, not even as a zombie.
+        assert!(rustix::process::test_kill_process(pid).is_err());
+    }
+
+    #[test]
+    fn test_instance_name_unique() {
+        let a = instance_name("owner");
+        let b = instance_name("owner");
+        assert_ne!(a, b);
+        assert!(a.starts_with(&format!("owner-{}-", process::id())));
+    }
+
+    #[test]
+    fn test_daemon_logs_from_str() {
+        assert_eq!("files".parse::<DaemonLogs>().unwrap(), DaemonLogs::Files);
+        assert_eq!(
+            "inherit".parse::<DaemonLogs>().unwrap(),
+            DaemonLogs::Inherit
+        );
+        assert!("stdout".parse::<DaemonLogs>().is_err());
+    }
+
+    #[test]
+    fn test_expand_env_vars() {
+        env::set_var("EXAMPLE_COMMON_TEST_STATE", "/var/lib/gate");
+
+        let literal = Path::new("/tmp/gate-daemon");
+        assert_eq!(expand_env_vars(literal).unwrap(), literal);
+
+        let expanded = expand_env_vars(Path::new("${EXAMPLE_COMMON_TEST_STATE}/owner")).unwrap();
+        assert_eq!(expanded, Path::new("/var/lib/gate/owner"));
+
+        assert!(expand_env_vars(Path::new("${EXAMPLE_COMMON_TEST_UNDEFINED}/owner")).is_err());
+        assert!(expand_env_vars(Path::new("${EXAMPLE_COMMON_TEST_STATE/owner")).is_err());
+    }
+}
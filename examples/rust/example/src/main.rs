@@ -1,31 +1,19 @@
-use std::{
-    env,
-    net::{Ipv4Addr, SocketAddr},
-    path::{Path, PathBuf},
-    str::FromStr,
-    time::Duration,
-};
+use std::{env, path::PathBuf, str::FromStr, time::Duration};
 
 use anyhow::{bail, Context as _, Result};
 use aranya_client::{
     afc,
     aqc::AqcPeerChannel,
-    client::{ChanOp, Client, DeviceId, KeyBundle, NetIdentifier, Role},
+    client::{ChanOp, NetIdentifier, Role},
     AddTeamConfig, AddTeamQuicSyncConfig, CreateTeamConfig, CreateTeamQuicSyncConfig, Error,
     SyncPeerConfig,
 };
 use aranya_daemon_api::text;
-use aranya_util::Addr;
-use backon::{ExponentialBuilder, Retryable};
+use aranya_example_common::{ClientCtx, DaemonLogs, DaemonPath};
 use buggy::BugExt;
 use bytes::Bytes;
 use futures_util::future::try_join;
-use tempfile::TempDir;
-use tokio::{
-    fs,
-    process::{Child, Command},
-    time::sleep,
-};
+use tokio::time::sleep;
 use tracing::{debug, info, Metadata};
 use tracing_subscriber::{
     layer::{Context, Filter},
@@ -33,139 +21,9 @@ use tracing_subscriber::{
     EnvFilter,
 };
 
-#[derive(Clone, Debug)]
-struct DaemonPath(PathBuf);
-
-#[derive(Debug)]
-#[clippy::has_significant_drop]
-struct Daemon {
-    // NB: This has important drop side effects.
-    _proc: Child,
-    _work_dir: PathBuf,
-}
-
-impl Daemon {
-    async fn spawn(path: &DaemonPath, work_dir: &Path, cfg_path: &Path) -> Result<Self> {
-        fs::create_dir_all(&work_dir).await?;
-
-        let cfg_path = cfg_path.as_os_str().to_str().context("should be UTF-8")?;
-        let mut cmd = Command::new(&path.0);
-        cmd.kill_on_drop(true)
-            .current_dir(work_dir)
-            .args(["--config", cfg_path]);
-        debug!(?cmd, "spawning daemon");
-        let proc = cmd.spawn().context("unable to spawn daemon")?;
-        Ok(Daemon {
-            _proc: proc,
-            _work_dir: work_dir.into(),
-        })
-    }
-}
-
-/// An Aranya device.
-struct ClientCtx {
-    client: Client,
-    aqc_addr: SocketAddr,
-    pk: KeyBundle,
-    id: DeviceId,
-    // NB: These have important drop side effects.
-    _work_dir: TempDir,
-    _daemon: Daemon,
-}
-
-impl ClientCtx {
-    pub async fn new(team_name: &str, user_name: &str, daemon_path: &DaemonPath) -> Result<Self> {
-        info!(team_name, user_name, "creating `ClientCtx`");
-
-        let work_dir = TempDir::with_prefix(user_name)?;
-
-        let daemon = {
-            let shm = format!("/shm_{}", user_name);
-            let _ = rustix::shm::unlink(&shm);
-            let work_dir = work_dir.path().join("daemon");
-            fs::create_dir_all(&work_dir).await?;
-
-            let cfg_path = work_dir.join("config.toml");
-
-            let runtime_dir = work_dir.join("run");
-            let state_dir = work_dir.join("state");
-            let cache_dir = work_dir.join("cache");
-            let logs_dir = work_dir.join("logs");
-            let config_dir = work_dir.join("config");
-            for dir in &[&runtime_dir, &state_dir, &cache_dir, &logs_dir, &config_dir] {
-                fs::create_dir_all(dir)
-                    .await
-                    .with_context(|| format!("unable to create directory: {}", dir.display()))?;
-            }
-
-            let buf = format!(
-                r#"
-                name = {user_name:?}
-                runtime_dir = {runtime_dir:?}
-                state_dir = {state_dir:?}
-                cache_dir = {cache_dir:?}
-                logs_dir = {logs_dir:?}
-                config_dir = {config_dir:?}
-
-                aqc.enable = true
-
-                [afc]
-                enable = true
-                shm_path = {shm:?}
-                max_chans = 100
-
-                [sync.quic]
-                enable = true
-                addr = "127.0.0.1:0"
-                "#
-            );
-            fs::write(&cfg_path, buf).await?;
-
-            Daemon::spawn(daemon_path, &work_dir, &cfg_path).await?
-        };
-
-        // The path that the daemon will listen on.
-        let uds_sock = work_dir.path().join("daemon").join("run").join("uds.sock");
-
-        // Give the daemon time to start up and write its public key.
-        sleep(Duration::from_millis(100)).await;
-
-        let any_addr = Addr::from((Ipv4Addr::LOCALHOST, 0));
-        let client = (|| {
-            Client::builder()
-                .daemon_uds_path(&uds_sock)
-                .aqc_server_addr(&any_addr)
-                .connect()
-        })
-        .retry(ExponentialBuilder::default())
-        .await
-        .context("unable to initialize client")?;
-
-        let aqc_server_addr = client.aqc().context("AQC is enabled")?.server_addr();
-        let pk = client
-            .get_key_bundle()
-            .await
-            .context("expected key bundle")?;
-        let id = client.get_device_id().await.context("expected device id")?;
-
-        Ok(Self {
-            client,
-            aqc_addr: aqc_server_addr,
-            pk,
-            id,
-            _work_dir: work_dir,
-            _daemon: daemon,
-        })
-    }
-
-    async fn aranya_local_addr(&self) -> Result<SocketAddr> {
-        Ok(self.client.local_addr().await?)
-    }
-
-    fn aqc_net_id(&self) -> NetIdentifier {
-        NetIdentifier::from_str(self.aqc_addr.to_string().as_str())
-            .expect("expected net identifier")
-    }
+/// Returns `ctx`'s AQC server address as a network identifier.
+fn aqc_net_id(ctx: &ClientCtx) -> Result<NetIdentifier> {
+    NetIdentifier::from_str(ctx.aqc_local_addr()?.to_string().as_str())
 }
 
 struct DemoFilter {
@@ -212,12 +70,11 @@ async fn main() -> Result<()> {
     let sleep_interval = sync_interval * 6;
     let sync_cfg = SyncPeerConfig::builder().interval(sync_interval).build()?;
 
-    let team_name = "rust_example";
-    let owner = ClientCtx::new(team_name, "owner", &daemon_path).await?;
-    let admin = ClientCtx::new(team_name, "admin", &daemon_path).await?;
-    let operator = ClientCtx::new(team_name, "operator", &daemon_path).await?;
-    let membera = ClientCtx::new(team_name, "member_a", &daemon_path).await?;
-    let memberb = ClientCtx::new(team_name, "member_b", &daemon_path).await?;
+    let owner = ClientCtx::with_temp_dir("owner", &daemon_path, DaemonLogs::Inherit).await?;
+    let admin = ClientCtx::with_temp_dir("admin", &daemon_path, DaemonLogs::Inherit).await?;
+    let operator = ClientCtx::with_temp_dir("operator", &daemon_path, DaemonLogs::Inherit).await?;
+    let membera = ClientCtx::with_temp_dir("member_a", &daemon_path, DaemonLogs::Inherit).await?;
+    let memberb = ClientCtx::with_temp_dir("member_b", &daemon_path, DaemonLogs::Inherit).await?;
 
     // Create the team config
     let seed_ikm = {
@@ -240,7 +97,7 @@ async fn main() -> Result<()> {
     let memberb_addr = memberb.aranya_local_addr().await?;
 
     // get aqc addresses.
-    debug!(?membera.aqc_addr, ?memberb.aqc_addr);
+    debug!(membera_aqc_addr = ?membera.aqc_local_addr()?, memberb_aqc_addr = ?memberb.aqc_local_addr()?);
 
     // Create a team.
     info!("creating team");
@@ -368,10 +225,10 @@ async fn main() -> Result<()> {
 
     info!("assigning aqc net identifiers");
     operator_team
-        .assign_aqc_net_identifier(membera.id, membera.aqc_net_id())
+        .assign_aqc_net_identifier(membera.id, aqc_net_id(&membera)?)
         .await?;
     operator_team
-        .assign_aqc_net_identifier(memberb.id, memberb.aqc_net_id())
+        .assign_aqc_net_identifier(memberb.id, aqc_net_id(&memberb)?)
         .await?;
 
     // wait for syncing.
@@ -421,7 +278,7 @@ async fn main() -> Result<()> {
                 .client
                 .aqc()
                 .context("AQC is enabled")?
-                .create_bidi_channel(team_id, memberb.aqc_net_id(), label3)
+                .create_bidi_channel(team_id, aqc_net_id(&memberb)?, label3)
                 .await?;
             Ok(chan)
         },